@@ -0,0 +1,125 @@
+use crate::writer::{translate_lines, translate_partial_line, translate_rest, BUFFER_THRESHOLD};
+use crate::Pathbox;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::AsyncWrite;
+
+/// An asynchronous output stream that's linked to a [`Pathbox`] and
+/// translates guest paths back into their external presentation.
+///
+/// This is the [`AsyncWrite`] counterpart of [`Writer`](crate::Writer), and
+/// translates and buffers output the same way.
+pub struct AsyncWriter<'a, W> {
+    pathbox: &'a Pathbox,
+    inner: W,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncWriter<'a, W> {
+    pub(crate) fn new(pathbox: &'a Pathbox, inner: W) -> Self {
+        Self {
+            pathbox,
+            inner,
+            buf: Vec::new(),
+            out: Vec::new(),
+        }
+    }
+
+    /// Write all translated output accumulated so far to the inner stream.
+    fn poll_flush_out(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.out.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out)) {
+                Ok(0) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    )))
+                }
+                Ok(n) => {
+                    self.out.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a, W: AsyncWrite + Unpin> AsyncWrite for AsyncWriter<'a, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        // Apply backpressure once enough output has accumulated.
+        if this.out.len() >= BUFFER_THRESHOLD {
+            ready!(this.poll_flush_out(cx))?;
+        }
+
+        translate_lines(this.pathbox, &mut this.buf, &mut this.out, buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        translate_partial_line(this.pathbox, &mut this.buf, &mut this.out);
+        ready!(this.poll_flush_out(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // No more output is coming, so write out any partial line too,
+        // including a trailing internal filename, as `Writer` does when
+        // it's dropped.
+        translate_rest(this.pathbox, &mut this.buf, &mut this.out);
+        ready!(this.poll_flush_out(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MagicLevel;
+    use std::task::Waker;
+
+    #[test]
+    fn test_translate() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(["/foo/bar.txt".to_owned()].into_iter())
+            .unwrap();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut writer = pathbox.async_writer(Vec::new());
+        let line = format!("opened {}\n", args[0]);
+        let result = Pin::new(&mut writer).poll_write(&mut cx, line.as_bytes());
+        assert!(matches!(result, Poll::Ready(Ok(n)) if n == line.len()));
+        let result = Pin::new(&mut writer).poll_flush(&mut cx);
+        assert!(matches!(result, Poll::Ready(Ok(()))));
+        assert_eq!(writer.inner, b"opened /foo/bar.txt\n");
+    }
+
+    #[test]
+    fn test_shutdown_partial_line() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(["/foo/bar.txt".to_owned()].into_iter())
+            .unwrap();
+
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut writer = pathbox.async_writer(Vec::new());
+        let line = format!("opened {}", args[0]);
+        let result = Pin::new(&mut writer).poll_write(&mut cx, line.as_bytes());
+        assert!(matches!(result, Poll::Ready(Ok(n)) if n == line.len()));
+        let result = Pin::new(&mut writer).poll_shutdown(&mut cx);
+        assert!(matches!(result, Poll::Ready(Ok(()))));
+        assert_eq!(writer.inner, b"opened /foo/bar.txt");
+    }
+}
@@ -0,0 +1,121 @@
+//! Functions which mirror those in `std::fs`, but which take a [`Pathbox`]
+//! and an internal filename instead of a path.
+//!
+//! Paths within granted directories are resolved relative to the directory,
+//! so that `..` and symlinks can't be used to escape it, as with
+//! [`Pathbox::open`].
+
+use crate::pathbox::{hide_host_path, retry_on_interrupt};
+use crate::{Access, Pathbox};
+use dir_view::cap_std::ambient_authority;
+use dir_view::cap_std::fs::{Dir, Metadata};
+use dir_view::ReadDirView;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Read the entire contents of a file into a bytes vector, like
+/// `std::fs::read`.
+pub fn read(pathbox: &Pathbox, path: &str) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    pathbox.open(path)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Read the entire contents of a file into a string, like
+/// `std::fs::read_to_string`.
+pub fn read_to_string(pathbox: &Pathbox, path: &str) -> io::Result<String> {
+    let mut string = String::new();
+    pathbox.open(path)?.read_to_string(&mut string)?;
+    Ok(string)
+}
+
+/// Write a slice as the entire contents of a file, like `std::fs::write`.
+pub fn write(pathbox: &Pathbox, path: &str, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    pathbox.create(path)?.write_all(contents.as_ref())
+}
+
+/// Return an iterator over the entries within a directory, like
+/// `std::fs::read_dir`.
+pub fn read_dir(pathbox: &Pathbox, path: &str) -> io::Result<ReadDirView> {
+    pathbox.open_dir(path)?.entries()
+}
+
+/// Query the metadata of a file or directory, like `std::fs::metadata`.
+pub fn metadata(pathbox: &Pathbox, path: &str) -> io::Result<Metadata> {
+    let found = pathbox
+        .find_in_dir(path, Access::Read)
+        .or_else(|_| pathbox.find_in_dir(path, Access::ReadonlyDir))?;
+    if let Some((dir, rest)) = found {
+        return if rest.is_empty() {
+            dir.dir_metadata()
+        } else {
+            dir.metadata(rest)
+        };
+    }
+    let full_path = pathbox.host_path(path, Access::Read)?;
+    std::fs::metadata(&full_path)
+        .map(Metadata::from_just_metadata)
+        .map_err(|err| hide_host_path(err, &full_path, path))
+}
+
+/// Copy the contents of one file to another, like `std::fs::copy`.
+///
+/// Unlike `std::fs::copy`, permissions aren't copied.
+pub fn copy(pathbox: &Pathbox, from: &str, to: &str) -> io::Result<u64> {
+    let mut from = pathbox.open(from)?;
+    let mut to = pathbox.create(to)?;
+    io::copy(&mut from, &mut to)
+}
+
+/// Rename a file, like `std::fs::rename`.
+///
+/// This requires write access to both files, or for files within granted
+/// directories, mutable access to the directories. If renaming is
+/// interrupted by a signal, it's retried.
+pub fn rename(pathbox: &Pathbox, from: &str, to: &str) -> io::Result<()> {
+    let (from_dir, from_name) = open_parent(pathbox, from)?;
+    let (to_dir, to_name) = open_parent(pathbox, to)?;
+    retry_on_interrupt(|| from_dir.rename(&from_name, &to_dir, &to_name))
+}
+
+/// Remove a file, like `std::fs::remove_file`.
+///
+/// This requires write access to the file, or for a file within a granted
+/// directory, mutable access to the directory. If removing is interrupted
+/// by a signal, it's retried.
+pub fn remove_file(pathbox: &Pathbox, path: &str) -> io::Result<()> {
+    let (dir, name) = open_parent(pathbox, path)?;
+    retry_on_interrupt(|| dir.remove_file(&name))
+}
+
+/// Open a directory containing the file named by the internal filename
+/// `path`, and return it along with the file's path relative to it.
+///
+/// Within a granted directory, that's the granted directory, which requires
+/// `MutableDir` access; otherwise, it's the parent of the granted file,
+/// which requires `Write` access.
+fn open_parent(pathbox: &Pathbox, path: &str) -> io::Result<(Arc<Dir>, PathBuf)> {
+    let found = pathbox
+        .find_in_dir(path, Access::MutableDir)
+        .or_else(|_| pathbox.find_in_dir(path, Access::Write))?;
+    if let Some((dir, rest)) = found {
+        if !rest.is_empty() {
+            return Ok((dir, PathBuf::from(rest)));
+        }
+    }
+    let full_path = pathbox.host_path(path, Access::Write)?;
+    let name = full_path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{:?}' doesn't name a file", path),
+        )
+    })?;
+    let parent = match full_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dir = Dir::open_ambient_dir(parent, ambient_authority())
+        .map_err(|err| hide_host_path(err, parent, path))?;
+    Ok((Arc::new(dir), PathBuf::from(name)))
+}
@@ -0,0 +1,863 @@
+//! Heuristics for recognizing filesystem paths.
+//!
+//! These are pure string functions, and don't depend on `std`, so that they
+//! can be used in environments without a filesystem.
+
+/// If `s` is a path ending with a basename extension, split it into the
+/// path without the extension, and the extension.
+pub fn split_extension(s: &str) -> (&str, &str) {
+    let after_last_slash = match s.rfind('/') {
+        Some(slash) => slash + 1,
+        None => 0,
+    };
+    let basename = &s[after_last_slash..];
+
+    // If there are no embedded '.'s in the basename, there's no extension.
+    if !basename.contains('.') {
+        return (s, "");
+    }
+
+    // The extension is the substring after the first dot which is not:
+    //  - at the beginning of the basename,
+    //  - immediately followed by another dot, or
+    //  - followed by a USV which is never an extension USV.
+    let first_len = basename.chars().next().map_or(0, char::len_utf8);
+    let mut remainder = &basename[first_len..];
+    while let Some(last_dot) = remainder.find('.') {
+        let ext = &remainder[last_dot + 1..];
+        if !ext.starts_with('.') && !ext.chars().any(is_never_extension) {
+            let suffix = &remainder[last_dot..];
+            return (&s[..s.len() - suffix.len()], suffix);
+        }
+        remainder = ext;
+    }
+
+    // Otherwise, there is no extension.
+    (s, "")
+}
+
+/// Like [`split_extension`], but only split off the last extension, so that
+/// `foo.txt.gz` is split into `foo.txt` and `.gz`.
+pub fn split_last_extension(s: &str) -> (&str, &str) {
+    let (_base, ext) = split_extension(s);
+    match ext.rfind('.') {
+        Some(last_dot) => s.split_at(s.len() - (ext.len() - last_dot)),
+        None => (s, ""),
+    }
+}
+
+/// Like [`split_extension`], but only split off a compound extension if it
+/// is one of `compounds`, and otherwise only split off the last extension.
+///
+/// `compounds` are given without a leading dot, for example `["tar.gz"]`.
+/// With that, `foo.tar.gz` is split into `foo` and `.tar.gz`, while
+/// `foo.txt.gz` is split into `foo.txt` and `.gz`.
+pub fn split_known_compound<'a>(s: &'a str, compounds: &[&str]) -> (&'a str, &'a str) {
+    let (_base, ext) = split_extension(s);
+    for compound in compounds {
+        if let Some(before) = ext.strip_suffix(compound) {
+            if before.ends_with('.') {
+                return s.split_at(s.len() - compound.len() - 1);
+            }
+        }
+    }
+    split_last_extension(s)
+}
+
+/// Test whether `c` is a `char` which is never part of a filename extension.
+fn is_never_extension(c: char) -> bool {
+    // These should be excluded already.
+    assert_ne!(c, '/');
+
+    // The following characters should never be permitted as a filename
+    // extension.
+    c.is_whitespace() || c.is_control()
+        // Selected ASCII codes which would be trouble an extension and as such
+        // are very unlikely to be an extension in the wild.
+        || c == '*'
+        || c == '"'
+        || c == '\''
+        || c == '`'
+        || c == ':'
+        || c == ';'
+        || c == '\\'
+        || c == '('
+        || c == ')'
+        || c == '{'
+        || c == '}'
+        || c == '['
+        || c == ']'
+        || c == '|'
+        || c == '>'
+        || c == '<'
+        // Just... no.
+        || c == '\u{feff}' // BOM
+        // Unicode specials are special.
+        || c == '\u{fff9}' // Interlinear annotations
+        || c == '\u{fffa}'
+        || c == '\u{fffb}'
+        || c == '\u{fffc}' // Object-replacement character
+        || c == char::REPLACEMENT_CHARACTER
+}
+
+/// Test whether `c` is a suspicious shell metacharacter which is unlikely to
+/// be worth assuming participates in a filename.
+fn is_suspicious_shell_metacharacter(c: char, windows: bool) -> bool {
+    // On Windows, backslash is a path separator.
+    if windows && c == '\\' {
+        return false;
+    }
+
+    matches!(
+        c,
+        '&' | '<' | '>' | '\\' | '|' | '?' | '*' | '[' | ']' | '"' | '\'' | ';'
+    )
+}
+
+/// Apply some simple heuristics to determine whether `arg` is likely to refer
+/// to a filesystem path.
+///
+/// The heuristic roughly works like this:
+///
+///  - If it starts with a `-`, assume it's not a path.
+///  - If it contains a `/`, assume it is a path.
+///  - If it ends with a conventional-looking filename extension, or it looks
+///    like a dotile, assume it is a path.
+///  - Otherwise, assume it isn't.
+///
+/// There are also a few additional heuristics for rare situations.
+///
+/// This uses the default [`Heuristics`]; use [`Heuristics::is_likely_path`]
+/// to customize them.
+pub fn is_likely_path(arg: &str) -> bool {
+    Heuristics::new().is_likely_path(arg)
+}
+
+/// Configuration for the path heuristics.
+///
+/// The defaults are chosen to be safe on all platforms.
+#[derive(Debug, Clone)]
+pub struct Heuristics {
+    allow_trailing_dot_or_space: bool,
+    allow_control_chars: bool,
+    well_known_filenames: &'static [&'static str],
+    unix_paths: bool,
+}
+
+impl Heuristics {
+    /// Construct a new `Heuristics` with the default configuration.
+    pub const fn new() -> Self {
+        Self {
+            allow_trailing_dot_or_space: false,
+            allow_control_chars: false,
+            well_known_filenames: &[],
+            unix_paths: false,
+        }
+    }
+
+    /// Construct a new `Heuristics` with the default configuration, except
+    /// that paths are always recognized as Unix-style paths, even on
+    /// Windows.
+    ///
+    /// This is for hosts which always present Unix-style paths, such as WASI
+    /// hosts, regardless of the platform they run on. On Windows, it changes
+    /// [`Heuristics::is_likely_path`] so that backslashes aren't separators,
+    /// drive letters, UNC and verbatim prefixes, and device names like `CON`
+    /// aren't recognized, a leading `/` isn't assumed to be a flag, and the
+    /// settings which are otherwise ignored on Windows take effect. On other
+    /// platforms, it's the same as [`Heuristics::new`].
+    pub const fn unix() -> Self {
+        let mut heuristics = Self::new();
+        heuristics.unix_paths = true;
+        heuristics
+    }
+
+    /// A small set of well-known filenames which have no extension, for use
+    /// with [`Heuristics::set_well_known_filenames`].
+    pub const WELL_KNOWN_FILENAMES: &'static [&'static str] = &[
+        "AUTHORS",
+        "CHANGELOG",
+        "COPYING",
+        "Dockerfile",
+        "Gemfile",
+        "GNUmakefile",
+        "Jenkinsfile",
+        "LICENSE",
+        "Makefile",
+        "makefile",
+        "Procfile",
+        "README",
+        "Rakefile",
+        "Vagrantfile",
+    ];
+
+    /// Set whether path components may end in a `.` or whitespace.
+    ///
+    /// By default, such components are assumed not to be part of a path,
+    /// because Windows silently strips trailing dots and spaces from
+    /// filenames, so that `foo.` and `foo ` refer to `foo`. On Unix these are
+    /// ordinary filenames, and this may be enabled to recognize them. On
+    /// Windows, this setting has no effect.
+    pub fn set_allow_trailing_dot_or_space(&mut self, allow: bool) {
+        self.allow_trailing_dot_or_space = allow;
+    }
+
+    /// Set whether paths may contain control characters other than NUL.
+    ///
+    /// By default, paths containing control characters are assumed not to be
+    /// paths, because such filenames are rare and more likely to indicate
+    /// something amiss. On Unix they're valid, and this may be enabled to
+    /// recognize them. NUL is always rejected, since it can't appear in a
+    /// path passed to the OS. On Windows, control characters are invalid in
+    /// filenames, so this setting has no effect.
+    pub fn set_allow_control_chars(&mut self, allow: bool) {
+        self.allow_control_chars = allow;
+    }
+
+    /// Set a list of filenames without extensions, such as `Makefile`, which
+    /// should be recognized as paths.
+    ///
+    /// By default, the list is empty, so such filenames aren't recognized
+    /// unless they contain a `/`. [`Heuristics::WELL_KNOWN_FILENAMES`] is a
+    /// curated list to use. Names are compared case-sensitively, except on
+    /// Windows.
+    pub fn set_well_known_filenames(&mut self, names: &'static [&'static str]) {
+        self.well_known_filenames = names;
+    }
+
+    /// Apply some simple heuristics to determine whether `arg` is likely to
+    /// refer to a filesystem path, as described in [`is_likely_path`].
+    pub fn is_likely_path(&self, arg: &str) -> bool {
+        // Exceptionally long strings are never filesystem paths.
+        if arg.len() > 4096 {
+            return false;
+        }
+
+        if let Some(c) = arg.chars().next() {
+            // If the name starts with '-', assume it's meant to be a flag.
+            if c == '-' {
+                return false;
+            }
+
+            // On Windows, also assume a leading slash is meant to be a flag.
+            if self.windows_paths() && c == '/' {
+                return false;
+            }
+
+            // If the name has leading whitespace, assume it's not a path.
+            if c.is_whitespace() {
+                return false;
+            }
+
+            // If the name starts with suspicious shell beginning-of-string
+            // metacharacters, don't give it the benefit of the doubt.
+            if matches!(c, '~' | '!') {
+                return false;
+            }
+
+            // We use a leading `%` as our escape character.
+            if c == '%' {
+                return false;
+            }
+
+            // If the name starts with suspicious shell metacharacters, don't give
+            // it the benefit of the doubt.
+            if is_suspicious_shell_metacharacter(c, self.windows_paths()) {
+                return false;
+            }
+        } else {
+            // Empty strings are never filesystem paths.
+            return false;
+        }
+
+        // If any path-looking component begins or ends with whitespace, or ends
+        // with a `.` (without being `.` or `..` themselves) then assume it's not
+        // a path, unless configured otherwise.
+        for component in components(arg, self.windows_paths()) {
+            // `.` and `..` are common path components.
+            if component == "." || component == ".." {
+                continue;
+            }
+
+            if let Some(first) = component.chars().next() {
+                if first.is_whitespace() {
+                    return false;
+                }
+            } else {
+                return false;
+            }
+            let last = component.chars().next_back().unwrap();
+            if (last.is_whitespace() || last == '.') && !self.trailing_dot_or_space_allowed() {
+                return false;
+            }
+        }
+
+        // Filenames containing control characters aren't impossible, but are very
+        // rare and more likely to indicate something amiss than something normal.
+        if arg.contains('\0') {
+            return false;
+        }
+        if arg.chars().any(char::is_control) && !self.control_chars_allowed() {
+            return false;
+        }
+
+        // Now that we've ruled out patterns that are very likely to indicate that
+        // something is not meant to be a path, check for patterns which indicate
+        // that is likely to indicate that it is meant to be a path.
+
+        // If it contains a `/`, treat it as a path.
+        if arg.contains('/') {
+            return true;
+        }
+
+        // Recognize Windows' special filenames as paths.
+        #[cfg(windows)]
+        if self.windows_paths() {
+            let (start, _ext) = split_extension(arg);
+            for special in [
+                "CON", "PRN", "AUX", "NUL", "COM0", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6",
+                "COM7", "COM8", "COM9", "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6",
+                "LPT7", "LPT8", "LPT9",
+            ] {
+                if start.eq_ignore_ascii_case(special) {
+                    return true;
+                }
+            }
+        }
+
+        // On Windows, if it starts with a path prefix, treat it as a path.
+        #[cfg(windows)]
+        match windows_prefix(arg).filter(|_| self.windows_paths()) {
+            // `\\?\pictures\kittens`, `\\?\UNC\server\share`, and `\\?\c:\`.
+            Some(
+                WindowsPrefix::Verbatim | WindowsPrefix::VerbatimUnc | WindowsPrefix::VerbatimDisk,
+            ) => return true,
+            // `\\.\BrainInterface`.
+            Some(WindowsPrefix::DeviceNs) => return true,
+            // `\\server\share`.
+            Some(WindowsPrefix::Unc) => return true,
+            // `C:\Users`.
+            Some(WindowsPrefix::Disk) => return true,
+            None => {}
+        }
+
+        // On Windows, if it contains `\\`, treat it as a path.
+        if self.windows_paths() && arg.contains('\\') {
+            return true;
+        }
+
+        // Recognize configured well-known filenames which have no extension.
+        if self.well_known_filenames.iter().any(|name| {
+            if self.windows_paths() {
+                arg.eq_ignore_ascii_case(name)
+            } else {
+                arg == *name
+            }
+        }) {
+            return true;
+        }
+
+        // Recognize plain filenames if they have a conventional-looking
+        // filename extension. This is deliberately just a length and charset
+        // check rather than a lookup in a MIME database, so that it doesn't need
+        // a large table of known extensions.
+        if let Some(ext) = extension(arg, self.windows_paths()) {
+            if !ext.is_empty() && ext.len() <= 16 && ext.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return true;
+            }
+        }
+
+        // Similarly, recognize Unix-style dot files.
+        if let Some(suffix) = arg.strip_prefix('.') {
+            if suffix.chars().all(|c| {
+                c.is_ascii_graphic()
+                    && !matches!(
+                        c,
+                        '&' | '<' | '>' | '\\' | '|' | '?' | '*' | '[' | ']' | '"' | '\'' | ';'
+                    )
+            }) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Test whether paths are Windows paths, which is the case on Windows
+    /// unless Unix-style paths were requested with [`Heuristics::unix`].
+    pub(crate) fn windows_paths(&self) -> bool {
+        cfg!(windows) && !self.unix_paths
+    }
+
+    /// Test whether components ending in `.` or whitespace are permitted,
+    /// which is never the case for Windows paths.
+    fn trailing_dot_or_space_allowed(&self) -> bool {
+        !self.windows_paths() && self.allow_trailing_dot_or_space
+    }
+
+    /// Test whether control characters other than NUL are permitted, which
+    /// is never the case for Windows paths.
+    fn control_chars_allowed(&self) -> bool {
+        !self.windows_paths() && self.allow_control_chars
+    }
+}
+
+impl Default for Heuristics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Test whether `c` is a path separator.
+#[cfg(feature = "std")]
+pub(crate) fn is_separator(c: char) -> bool {
+    c == '/' || (cfg!(windows) && c == '\\')
+}
+
+/// Iterate over the non-empty components of `arg`, in the manner of
+/// `std::path::Path::components`, treating `\` as a separator too if
+/// `windows` is true.
+fn components(arg: &str, windows: bool) -> impl DoubleEndedIterator<Item = &str> {
+    arg.split(move |c| c == '/' || (windows && c == '\\'))
+        .filter(|c| !c.is_empty())
+}
+
+/// Return the extension of the last component of `arg`, in the manner of
+/// `std::path::Path::extension`.
+fn extension(arg: &str, windows: bool) -> Option<&str> {
+    let file_name = components(arg, windows).rfind(|c| *c != ".")?;
+    if file_name == ".." {
+        return None;
+    }
+    match file_name.rfind('.') {
+        None | Some(0) => None,
+        Some(dot) => Some(&file_name[dot + 1..]),
+    }
+}
+
+/// The kinds of Windows path prefixes, following `std::path::Prefix`.
+#[cfg(any(windows, test))]
+#[derive(Debug, Eq, PartialEq)]
+enum WindowsPrefix {
+    /// `\\?\prefix`
+    Verbatim,
+    /// `\\?\UNC\server\share`
+    VerbatimUnc,
+    /// `\\?\C:`
+    VerbatimDisk,
+    /// `\\.\device`
+    DeviceNs,
+    /// `\\server\share`
+    Unc,
+    /// `C:`
+    Disk,
+}
+
+/// Determine which kind of Windows path prefix, if any, `arg` starts with.
+///
+/// This is a string function, so it's usable on any platform.
+#[cfg(any(windows, test))]
+fn windows_prefix(arg: &str) -> Option<WindowsPrefix> {
+    // Verbatim paths disable all normalization, so they only recognize
+    // backslashes as separators.
+    if let Some(rest) = arg.strip_prefix(r"\\?\") {
+        if rest.starts_with(r"UNC\") {
+            return Some(WindowsPrefix::VerbatimUnc);
+        }
+        if is_disk(rest) && matches!(rest.as_bytes().get(2), None | Some(b'\\')) {
+            return Some(WindowsPrefix::VerbatimDisk);
+        }
+        return Some(WindowsPrefix::Verbatim);
+    }
+
+    let is_sep = |b: &u8| *b == b'\\' || *b == b'/';
+    match arg.as_bytes() {
+        [a, b, b'.', c, ..] if is_sep(a) && is_sep(b) && is_sep(c) => Some(WindowsPrefix::DeviceNs),
+        [a, b, c, ..] if is_sep(a) && is_sep(b) && !is_sep(c) => Some(WindowsPrefix::Unc),
+        _ if is_disk(arg) => Some(WindowsPrefix::Disk),
+        _ => None,
+    }
+}
+
+/// Test whether `arg` is a single drive-absolute or drive-relative Windows
+/// path, such as `C:\foo`, `C:foo`, or `C:`, rather than a colon-separated
+/// list.
+#[cfg(any(windows, test))]
+pub(crate) fn is_drive_path(arg: &str) -> bool {
+    is_disk(arg) && !arg[2..].contains(':')
+}
+
+/// Test whether `s` starts with a drive letter and a colon.
+#[cfg(any(windows, test))]
+fn is_disk(s: &str) -> bool {
+    matches!(s.as_bytes(), [drive, b':', ..] if drive.is_ascii_alphabetic())
+}
+
+#[test]
+fn test_is_likely_path() {
+    assert!(is_likely_path("/"));
+    assert!(is_likely_path("//"));
+    assert!(is_likely_path("."));
+    assert!(is_likely_path(".."));
+    assert!(is_likely_path("/."));
+    assert!(is_likely_path("/.."));
+    assert!(is_likely_path("./"));
+    assert!(is_likely_path("../"));
+    assert!(is_likely_path("hello.mp3"));
+    assert!(is_likely_path("world.JPEG"));
+    assert!(is_likely_path("goodnight.d"));
+    assert!(is_likely_path("moon.delightful"));
+    assert!(is_likely_path(".gitignore"));
+    assert!(is_likely_path(".this-and_that"));
+    assert!(is_likely_path("/foo"));
+    assert!(is_likely_path("/foo/bar"));
+    assert!(is_likely_path("/foo.baz/bar.baz"));
+    assert!(is_likely_path("foo/bar"));
+    assert!(is_likely_path("foo.baz/bar.baz"));
+    assert!(is_likely_path("foo/"));
+    assert!(is_likely_path("foo/bar/"));
+    assert!(is_likely_path("foo/bar/."));
+    assert!(is_likely_path("fo o/b ar"));
+    assert!(is_likely_path("f oo/ba r"));
+    assert!(is_likely_path(&"A/".repeat(2048)));
+
+    assert!(!is_likely_path(""));
+    assert!(!is_likely_path(".this and that"));
+    assert!(!is_likely_path("/hello\nworld.txt"));
+    assert!(!is_likely_path("/hello\tworld.txt"));
+    assert!(!is_likely_path("/hello\0world.txt"));
+    assert!(!is_likely_path(".this and that"));
+    assert!(!is_likely_path("<special/time.txt"));
+    assert!(!is_likely_path("!/what.txt"));
+    assert!(!is_likely_path("*/*/foo.md"));
+    assert!(!is_likely_path("foo"));
+    assert!(!is_likely_path(" /foo"));
+    assert!(!is_likely_path("foo /bar"));
+    assert!(!is_likely_path("foo/ bar"));
+    assert!(!is_likely_path("foo/bar."));
+    assert!(!is_likely_path("foo./bar"));
+    assert!(!is_likely_path("moon.excessivelylongextension"));
+    assert!(!is_likely_path(&"A/".repeat(2049)));
+
+    assert_eq!(is_likely_path("foo\\bar"), cfg!(windows));
+    assert_eq!(is_likely_path("\\foo\\bar"), cfg!(windows));
+    assert_eq!(is_likely_path("/A"), !cfg!(windows));
+    assert_eq!(is_likely_path("CON"), cfg!(windows));
+    assert_eq!(is_likely_path("NUL"), cfg!(windows));
+    assert_eq!(is_likely_path(r"\\?\pictures\kittens"), cfg!(windows));
+    assert_eq!(is_likely_path(r"\\?\UNC\server\share"), cfg!(windows));
+    assert_eq!(is_likely_path(r"\\?\c:\"), cfg!(windows));
+    assert_eq!(is_likely_path(r"\\.\BrainInterface"), cfg!(windows));
+    assert_eq!(is_likely_path(r"\\server\share"), cfg!(windows));
+    assert_eq!(
+        is_likely_path(r"C:\Users\Rust\Pictures\Ferris"),
+        cfg!(windows)
+    );
+}
+
+#[cfg(not(windows))]
+#[test]
+fn test_allow_trailing_dot_or_space() {
+    let mut heuristics = Heuristics::new();
+    heuristics.set_allow_trailing_dot_or_space(true);
+    assert!(heuristics.is_likely_path("foo/bar."));
+    assert!(heuristics.is_likely_path("foo./bar"));
+    assert!(heuristics.is_likely_path("foo /bar"));
+    assert!(heuristics.is_likely_path("foo/bar "));
+
+    // Leading whitespace is still rejected.
+    assert!(!heuristics.is_likely_path("foo/ bar"));
+    assert!(!heuristics.is_likely_path(" /foo"));
+
+    // Without a `/` or an extension, it's still not a path.
+    assert!(!heuristics.is_likely_path("foo."));
+}
+
+#[cfg(windows)]
+#[test]
+fn test_allow_trailing_dot_or_space() {
+    // Windows strips trailing dots and spaces, so they're always rejected.
+    let mut heuristics = Heuristics::new();
+    heuristics.set_allow_trailing_dot_or_space(true);
+    assert!(!heuristics.is_likely_path("foo/bar."));
+    assert!(!heuristics.is_likely_path("foo./bar"));
+    assert!(!heuristics.is_likely_path("foo /bar"));
+}
+
+#[test]
+fn test_allow_control_chars() {
+    let mut heuristics = Heuristics::new();
+    assert!(!heuristics.is_likely_path("/foo\x01bar"));
+    assert!(!heuristics.is_likely_path("/foo\0bar"));
+
+    heuristics.set_allow_control_chars(true);
+    assert_eq!(heuristics.is_likely_path("/foo\x01bar"), !cfg!(windows));
+    assert_eq!(heuristics.is_likely_path("foo\x1b.txt"), !cfg!(windows));
+    assert!(!heuristics.is_likely_path("/foo\0bar"));
+}
+
+#[test]
+fn test_well_known_filenames() {
+    let mut heuristics = Heuristics::new();
+    assert!(!heuristics.is_likely_path("Makefile"));
+    assert!(!heuristics.is_likely_path("Dockerfile"));
+
+    heuristics.set_well_known_filenames(Heuristics::WELL_KNOWN_FILENAMES);
+    assert!(heuristics.is_likely_path("Makefile"));
+    assert!(heuristics.is_likely_path("Dockerfile"));
+    assert!(heuristics.is_likely_path("README"));
+    assert!(heuristics.is_likely_path("LICENSE"));
+    assert!(!heuristics.is_likely_path("Makefiles"));
+    assert!(!heuristics.is_likely_path("make"));
+    assert_eq!(heuristics.is_likely_path("DOCKERFILE"), cfg!(windows));
+    assert_eq!(heuristics.is_likely_path("readme"), cfg!(windows));
+
+    heuristics.set_well_known_filenames(&["BUILD"]);
+    assert!(heuristics.is_likely_path("BUILD"));
+    assert!(!heuristics.is_likely_path("Makefile"));
+}
+
+#[test]
+fn test_unix() {
+    let heuristics = Heuristics::unix();
+    assert!(heuristics.is_likely_path("/A"));
+    assert!(heuristics.is_likely_path("foo/bar"));
+    assert!(heuristics.is_likely_path("foo.txt"));
+    assert!(!heuristics.is_likely_path("foo\\bar"));
+    assert!(!heuristics.is_likely_path("\\foo\\bar"));
+    assert!(!heuristics.is_likely_path("CON"));
+    assert!(!heuristics.is_likely_path(r"\\server\share"));
+    assert!(!heuristics.is_likely_path(r"C:\foo"));
+    assert!(!heuristics.windows_paths());
+
+    let mut heuristics = Heuristics::unix();
+    heuristics.set_allow_control_chars(true);
+    assert!(heuristics.is_likely_path("/foo\x01bar"));
+}
+
+#[test]
+fn test_split_last_extension() {
+    assert_eq!(split_last_extension("/foo/bar"), ("/foo/bar", ""));
+    assert_eq!(split_last_extension("/foo/bar.txt"), ("/foo/bar", ".txt"));
+    assert_eq!(
+        split_last_extension("/foo.qux/bar.txt.gz"),
+        ("/foo.qux/bar.txt", ".gz")
+    );
+    assert_eq!(split_last_extension("/foo/.bar"), ("/foo/.bar", ""));
+    assert_eq!(
+        split_last_extension("/foo/.bar.txt.gz"),
+        ("/foo/.bar.txt", ".gz")
+    );
+    assert_eq!(
+        split_last_extension("/foo.qux/bar.*.txt.gz"),
+        ("/foo.qux/bar.*.txt", ".gz")
+    );
+    assert_eq!(split_last_extension("a..txt"), ("a.", ".txt"));
+    assert_eq!(split_last_extension("\u{e9}.txt"), ("\u{e9}", ".txt"));
+}
+
+#[test]
+fn test_split_known_compound() {
+    let compounds = ["tar.gz", "tar.bz2"];
+    assert_eq!(
+        split_known_compound("/foo/bar", &compounds),
+        ("/foo/bar", "")
+    );
+    assert_eq!(
+        split_known_compound("/foo/bar.tar.gz", &compounds),
+        ("/foo/bar", ".tar.gz")
+    );
+    assert_eq!(
+        split_known_compound("/foo/bar.x.tar.bz2", &compounds),
+        ("/foo/bar.x", ".tar.bz2")
+    );
+    assert_eq!(
+        split_known_compound("/foo/bar.txt.gz", &compounds),
+        ("/foo/bar.txt", ".gz")
+    );
+    assert_eq!(
+        split_known_compound("/foo/bar.xtar.gz", &compounds),
+        ("/foo/bar.xtar", ".gz")
+    );
+    assert_eq!(
+        split_known_compound("/foo/.tar.gz", &compounds),
+        ("/foo/.tar", ".gz")
+    );
+}
+
+#[test]
+fn test_windows_prefix() {
+    use WindowsPrefix::*;
+
+    assert_eq!(windows_prefix(r"\\?\pictures\kittens"), Some(Verbatim));
+    assert_eq!(windows_prefix(r"\\?\"), Some(Verbatim));
+    assert_eq!(windows_prefix(r"\\?\UNC\server\share"), Some(VerbatimUnc));
+    assert_eq!(windows_prefix(r"\\?\c:\"), Some(VerbatimDisk));
+    assert_eq!(windows_prefix(r"\\?\c:"), Some(VerbatimDisk));
+    assert_eq!(windows_prefix(r"\\?\c:foo"), Some(Verbatim));
+    assert_eq!(windows_prefix(r"\\.\BrainInterface"), Some(DeviceNs));
+    assert_eq!(windows_prefix("//./BrainInterface"), Some(DeviceNs));
+    assert_eq!(windows_prefix(r"\\server\share"), Some(Unc));
+    assert_eq!(windows_prefix("//server/share"), Some(Unc));
+    assert_eq!(windows_prefix(r"C:\Users\Rust"), Some(Disk));
+    assert_eq!(windows_prefix("c:"), Some(Disk));
+
+    assert_eq!(windows_prefix(r"\\"), None);
+    assert_eq!(windows_prefix(r"\\\foo"), None);
+    assert_eq!(windows_prefix(r"\foo\bar"), None);
+    assert_eq!(windows_prefix("foo:bar"), None);
+    assert_eq!(windows_prefix("1:"), None);
+    assert_eq!(windows_prefix(""), None);
+}
+
+#[test]
+fn test_is_drive_path() {
+    assert!(is_drive_path("C:foo"));
+    assert!(is_drive_path(r"C:\foo"));
+    assert!(is_drive_path("C:"));
+    assert!(is_drive_path("c:/foo/bar.txt"));
+
+    assert!(!is_drive_path("C:foo:D:bar"));
+    assert!(!is_drive_path(r"C:\foo:D:\bar"));
+    assert!(!is_drive_path("foo:bar"));
+    assert!(!is_drive_path("1:foo"));
+    assert!(!is_drive_path(":foo"));
+}
+
+#[test]
+fn test_split_extension() {
+    assert_eq!(split_extension("/foo/bar"), ("/foo/bar", ""));
+    assert_eq!(split_extension("/foo/bar.txt"), ("/foo/bar", ".txt"));
+    assert_eq!(
+        split_extension("/foo.qux/bar.txt"),
+        ("/foo.qux/bar", ".txt")
+    );
+    assert_eq!(split_extension("/foo/.bar"), ("/foo/.bar", ""));
+    assert_eq!(split_extension("/foo/.bar.txt"), ("/foo/.bar", ".txt"));
+    assert_eq!(split_extension(".env.local"), (".env", ".local"));
+    assert_eq!(split_extension(".config.json"), (".config", ".json"));
+    assert_eq!(split_extension(".a.b.c"), (".a", ".b.c"));
+    assert_eq!(
+        split_extension("/foo.qux/.bar.txt"),
+        ("/foo.qux/.bar", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo/.bar.txt.gz"),
+        ("/foo/.bar", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/.bar.txt.gz"),
+        ("/foo.qux/.bar", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.txt"),
+        ("/foo.qux/bar", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.txt.gz"),
+        ("/foo.qux/bar", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar..txt.gz"),
+        ("/foo.qux/bar.", ".txt.gz")
+    );
+    assert_eq!(split_extension("/foo.qux/bar.*"), ("/foo.qux/bar.*", ""));
+    assert_eq!(
+        split_extension("/foo.qux/bar.*.txt"),
+        ("/foo.qux/bar.*", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.*.txt.gz"),
+        ("/foo.qux/bar.*", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffd}"),
+        ("/foo.qux/bar.\u{fffd}", "")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffd}.txt"),
+        ("/foo.qux/bar.\u{fffd}", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffd}.txt.gz"),
+        ("/foo.qux/bar.\u{fffd}", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{feff}"),
+        ("/foo.qux/bar.\u{feff}", "")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{feff}.txt"),
+        ("/foo.qux/bar.\u{feff}", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{feff}.txt.gz"),
+        ("/foo.qux/bar.\u{feff}", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffa}"),
+        ("/foo.qux/bar.\u{fffa}", "")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffa}.txt"),
+        ("/foo.qux/bar.\u{fffa}", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffa}.txt.gz"),
+        ("/foo.qux/bar.\u{fffa}", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffb}"),
+        ("/foo.qux/bar.\u{fffb}", "")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffb}.txt"),
+        ("/foo.qux/bar.\u{fffb}", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffb}.txt.gz"),
+        ("/foo.qux/bar.\u{fffb}", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffc}"),
+        ("/foo.qux/bar.\u{fffc}", "")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffc}.txt"),
+        ("/foo.qux/bar.\u{fffc}", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{fffc}.txt.gz"),
+        ("/foo.qux/bar.\u{fffc}", ".txt.gz")
+    );
+    assert_eq!(split_extension("/foo.qux/bar. "), ("/foo.qux/bar. ", ""));
+    assert_eq!(
+        split_extension("/foo.qux/bar. .txt"),
+        ("/foo.qux/bar. ", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar. .txt.gz"),
+        ("/foo.qux/bar. ", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{7}"),
+        ("/foo.qux/bar.\u{7}", "")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{7}.txt"),
+        ("/foo.qux/bar.\u{7}", ".txt")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{7}.txt.gz"),
+        ("/foo.qux/bar.\u{7}", ".txt.gz")
+    );
+    assert_eq!(
+        split_extension("/foo.qux/bar.\u{7}. .*..txt.gz"),
+        ("/foo.qux/bar.\u{7}. .*.", ".txt.gz")
+    );
+    assert_eq!(split_extension("."), (".", ""));
+    assert_eq!(split_extension(".txt"), (".txt", ""));
+    assert_eq!(split_extension("a.txt"), ("a", ".txt"));
+    assert_eq!(split_extension("a..txt"), ("a.", ".txt"));
+}
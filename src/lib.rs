@@ -1,9 +1,45 @@
+//! Isolate external paths from internal paths.
+//!
+//! [`Pathbox`] is the entry point: it processes a command line, replacing
+//! paths with internal filenames and recording what access is granted to
+//! each, and then opens internal filenames on behalf of the program.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "async")]
+mod async_writer;
+#[cfg(feature = "std")]
 mod exit;
+#[cfg(feature = "std")]
+pub mod fs;
+mod heuristics;
+#[cfg(feature = "std")]
 mod log;
+#[cfg(feature = "mem-fs")]
+mod mem_fs;
+#[cfg(feature = "std")]
 mod pathbox;
+#[cfg(feature = "std")]
+mod ranged;
+#[cfg(feature = "std")]
+mod spawn;
+#[cfg(feature = "std")]
 mod writer;
 
-pub use crate::pathbox::{Error, MagicLevel, Pathbox};
+#[cfg(feature = "std")]
+pub use crate::pathbox::{Access, Error, EscapeResult, Grant, MagicLevel, Pathbox, PreopenInfo};
+#[cfg(feature = "async")]
+pub use async_writer::AsyncWriter;
+#[cfg(feature = "std")]
 pub use exit::{exit, Status};
+pub use heuristics::{
+    is_likely_path, split_extension, split_known_compound, split_last_extension, Heuristics,
+};
+#[cfg(feature = "std")]
 pub use log::{log, Level};
+#[cfg(feature = "mem-fs")]
+pub use mem_fs::{MemFile, MemFs};
+#[cfg(feature = "std")]
+pub use ranged::RangedFile;
+#[cfg(feature = "std")]
 pub use writer::Writer;
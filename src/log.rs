@@ -1,28 +1,158 @@
+use std::ffi::OsStr;
+use std::fmt;
 use std::io::Write;
+use std::str::FromStr;
 
-#[derive(Debug, Hash, Eq, PartialEq, Clone, Copy)]
+/// The severity of a log message.
+///
+/// Levels are ordered from least to most severe. `Off` is above all the
+/// others, so that using it as a threshold disables logging.
+#[derive(Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Clone, Copy)]
 pub enum Level {
     Trace,
     Debug,
     Info,
     Warning,
     Error,
+    Off,
 }
 
-pub fn log<W: Write>(out: &mut W, level: Level, context: &str, message: &str) {
-    // Do a very simple thing for now.
-    writeln!(
-        out,
-        "[{} {}] {}",
-        match level {
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
             Level::Trace => "TRACE",
             Level::Debug => "DEBUG",
             Level::Info => "INFO",
             Level::Warning => "WARN",
             Level::Error => "ERROR",
-        },
-        context,
-        message
-    )
-    .unwrap();
+            Level::Off => "OFF",
+        })
+    }
+}
+
+impl FromStr for Level {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        for level in [
+            Level::Trace,
+            Level::Debug,
+            Level::Info,
+            Level::Warning,
+            Level::Error,
+            Level::Off,
+        ] {
+            if s.eq_ignore_ascii_case(&level.to_string()) {
+                return Ok(level);
+            }
+        }
+        if s.eq_ignore_ascii_case("warning") {
+            return Ok(Level::Warning);
+        }
+        Err(crate::Error(format!("Unrecognized log level {:?}", s)))
+    }
+}
+
+impl Level {
+    /// Return the ANSI escape sequence used to color this level's label.
+    fn color(self) -> &'static str {
+        match self {
+            Level::Trace => "\x1b[2m",
+            Level::Debug => "\x1b[34m",
+            Level::Info => "\x1b[32m",
+            Level::Warning => "\x1b[33m",
+            Level::Error | Level::Off => "\x1b[31m",
+        }
+    }
+}
+
+pub fn log<W: Write>(out: &mut W, level: Level, context: &str, message: &str) {
+    log_with_color(out, level, context, message, false)
+}
+
+/// Like [`log`], but with the level's label colored if `color` is true.
+pub(crate) fn log_with_color<W: Write>(
+    out: &mut W,
+    level: Level,
+    context: &str,
+    message: &str,
+    color: bool,
+) {
+    // Messages logged at `Off` are never shown.
+    if level == Level::Off {
+        return;
+    }
+
+    // Do a very simple thing for now.
+    if color {
+        writeln!(
+            out,
+            "[{}{}\x1b[0m {}] {}",
+            level.color(),
+            level,
+            context,
+            message
+        )
+        .unwrap();
+    } else {
+        writeln!(out, "[{} {}] {}", level, context, message).unwrap();
+    }
+}
+
+/// Decide whether to color log messages from the values of the `NO_COLOR`
+/// and `CLICOLOR_FORCE` environment variables, following the conventions
+/// at <https://no-color.org> and <https://bixense.com/clicolors/>.
+///
+/// Returns `None` if neither variable decides it.
+pub(crate) fn color_override(no_color: Option<&OsStr>, force: Option<&OsStr>) -> Option<bool> {
+    // `NO_COLOR` takes precedence, and, like `CLICOLOR_FORCE`, is ignored
+    // if it's empty.
+    if no_color.is_some_and(|value| !value.is_empty()) {
+        Some(false)
+    } else if force.is_some_and(|value| !value.is_empty() && value != "0") {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_level() {
+    for level in [
+        Level::Trace,
+        Level::Debug,
+        Level::Info,
+        Level::Warning,
+        Level::Error,
+        Level::Off,
+    ] {
+        assert_eq!(level.to_string().parse::<Level>(), Ok(level));
+        assert!(level <= Level::Off);
+    }
+    assert_eq!("warning".parse::<Level>(), Ok(Level::Warning));
+    assert_eq!("off".parse::<Level>(), Ok(Level::Off));
+    assert!("loud".parse::<Level>().is_err());
+}
+
+#[test]
+fn test_color() {
+    let mut out = Vec::new();
+    log_with_color(&mut out, Level::Warning, "test", "message", false);
+    log_with_color(&mut out, Level::Warning, "test", "message", true);
+    log_with_color(&mut out, Level::Off, "test", "message", true);
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "[WARN test] message\n[\x1b[33mWARN\x1b[0m test] message\n"
+    );
+
+    let yes = Some(OsStr::new("1"));
+    let zero = Some(OsStr::new("0"));
+    let empty = Some(OsStr::new(""));
+    assert_eq!(color_override(None, None), None);
+    assert_eq!(color_override(yes, None), Some(false));
+    assert_eq!(color_override(yes, yes), Some(false));
+    assert_eq!(color_override(None, yes), Some(true));
+    assert_eq!(color_override(empty, yes), Some(true));
+    assert_eq!(color_override(None, zero), None);
+    assert_eq!(color_override(empty, empty), None);
 }
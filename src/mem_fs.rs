@@ -0,0 +1,227 @@
+//! An in-memory filesystem, for testing programs which use a [`Pathbox`]
+//! without touching the host filesystem.
+
+use crate::{Access, Pathbox};
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// The contents of a file, shared by all of its handles.
+type Contents = Arc<Mutex<Vec<u8>>>;
+
+/// An in-memory filesystem, holding files by their host paths.
+///
+/// Internal filenames are resolved with [`Pathbox::host_path`], so grants
+/// are checked exactly as they are for [`Pathbox::open`] and friends, but
+/// files are read from and written to memory instead of the host
+/// filesystem. Cloning a `MemFs` produces a handle to the same files.
+#[derive(Debug, Clone, Default)]
+pub struct MemFs {
+    files: Arc<Mutex<HashMap<PathBuf, Contents>>>,
+}
+
+impl MemFs {
+    /// Construct a new empty `MemFs`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a file with the given host path and contents, replacing any
+    /// existing file with that path.
+    pub fn insert(&self, host: impl Into<PathBuf>, contents: impl Into<Vec<u8>>) {
+        let contents = Arc::new(Mutex::new(contents.into()));
+        self.files.lock().unwrap().insert(host.into(), contents);
+    }
+
+    /// Return a copy of the contents of the file with the given host path,
+    /// or `None` if there is no such file.
+    pub fn contents(&self, host: impl AsRef<Path>) -> Option<Vec<u8>> {
+        let files = self.files.lock().unwrap();
+        let contents = files.get(host.as_ref())?;
+        let contents = contents.lock().unwrap().clone();
+        Some(contents)
+    }
+
+    /// Open a file for reading given an internal filename, like
+    /// [`Pathbox::open`].
+    pub fn open(&self, pathbox: &Pathbox, path: &str) -> io::Result<MemFile> {
+        let host = pathbox.host_path(path, Access::Read)?;
+        let contents = self.files.lock().unwrap().get(&host).cloned();
+        let contents = contents.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("{:?} not found", path))
+        })?;
+        Ok(MemFile::new(contents, true, false, false))
+    }
+
+    /// Open a file for writing given an internal filename, creating it if
+    /// it doesn't exist and truncating it if it does, like
+    /// [`Pathbox::create`].
+    pub fn create(&self, pathbox: &Pathbox, path: &str) -> io::Result<MemFile> {
+        let host = pathbox.host_path(path, Access::Write)?;
+        let contents = Contents::default();
+        self.files.lock().unwrap().insert(host, contents.clone());
+        Ok(MemFile::new(contents, false, true, false))
+    }
+
+    /// Open a file for appending given an internal filename, creating it if
+    /// it doesn't exist, like [`Pathbox::append_create`].
+    pub fn append(&self, pathbox: &Pathbox, path: &str) -> io::Result<MemFile> {
+        let host = pathbox.host_path(path, Access::Append)?;
+        let contents = self.files.lock().unwrap().entry(host).or_default().clone();
+        Ok(MemFile::new(contents, false, true, true))
+    }
+}
+
+/// A handle to a file in a [`MemFs`], which reads and writes its contents
+/// like a `Cursor`.
+///
+/// Handles to the same file share its contents, so data written through
+/// one handle is visible through the others, and through
+/// [`MemFs::contents`].
+#[derive(Debug)]
+pub struct MemFile {
+    contents: Contents,
+    pos: u64,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+impl MemFile {
+    fn new(contents: Contents, readable: bool, writable: bool, append: bool) -> Self {
+        Self {
+            contents,
+            pos: 0,
+            readable,
+            writable,
+            append,
+        }
+    }
+}
+
+impl Read for MemFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.readable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not open for reading",
+            ));
+        }
+        let contents = self.contents.lock().unwrap();
+        let start = usize::try_from(self.pos)
+            .unwrap_or(usize::MAX)
+            .min(contents.len());
+        let n = buf.len().min(contents.len() - start);
+        buf[..n].copy_from_slice(&contents[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "file is not open for writing",
+            ));
+        }
+        let mut contents = self.contents.lock().unwrap();
+        if self.append {
+            self.pos = contents.len() as u64;
+        }
+        let start = usize::try_from(self.pos)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "offset is too large"))?;
+        let end = start + buf.len();
+        if contents.len() < end {
+            contents.resize(end, 0);
+        }
+        contents[start..end].copy_from_slice(buf);
+        self.pos = end as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::End(offset) => (self.contents.lock().unwrap().len() as u64, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::MagicLevel;
+
+    #[test]
+    fn test_mem_fs() {
+        let fs = MemFs::new();
+        fs.insert("/data/in.txt", "some data\n");
+
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let args = pathbox
+            .process_args(
+                ["%read:/data/in.txt", "%write:/data/out.txt", "%append:/log"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+
+        let mut data = String::new();
+        fs.open(&pathbox, &args[0])
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "some data\n");
+
+        fs.create(&pathbox, &args[1])
+            .unwrap()
+            .write_all(data.as_bytes())
+            .unwrap();
+        assert_eq!(fs.contents("/data/out.txt").unwrap(), b"some data\n");
+
+        let mut log = fs.append(&pathbox, &args[2]).unwrap();
+        log.write_all(b"one\n").unwrap();
+        log.seek(SeekFrom::Start(0)).unwrap();
+        log.write_all(b"two\n").unwrap();
+        assert_eq!(fs.contents("/log").unwrap(), b"one\ntwo\n");
+
+        // Grants are checked as they are for the host filesystem.
+        assert_eq!(
+            fs.create(&pathbox, &args[0]).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            fs.open(&pathbox, &args[1]).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            fs.open(&pathbox, "/data/in.txt").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert_eq!(fs.contents("/data/in.txt").unwrap(), b"some data\n");
+
+        // Files which are granted but don't exist aren't found.
+        let arg = pathbox.process_arg("%read:/missing".to_owned()).unwrap();
+        assert_eq!(
+            fs.open(&pathbox, &arg).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
@@ -1,11 +1,19 @@
-use crate::{log, Level, Writer};
-use cap_std::fs::{File, OpenOptions};
+#[cfg(windows)]
+use crate::heuristics::is_drive_path;
+use crate::heuristics::{is_separator, split_extension, Heuristics};
+use crate::{log, Level, RangedFile, Writer};
+use cap_std::fs::{Dir, File, OpenOptions};
 use dir_view::{ambient_authority, cap_std, DirView, ViewKind};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
 #[cfg(unix)]
-use std::ffi::OsStr;
-use std::ffi::OsString;
-use std::io;
-use std::path::PathBuf;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
 
 /// The level of path inference that should be performed.
 ///
@@ -30,6 +38,11 @@ pub enum MagicLevel {
 
     /// Interpret `%`-prefixed special arguments, and also infer read-only
     /// access for path-like strings.
+    ///
+    /// Write access is never inferred at this level; it must be requested
+    /// explicitly with an escape such as `%write:`, `%append:`, or
+    /// `%mutable-dir:`. [`Pathbox::set_limit_escapes`] disables such escapes
+    /// too.
     Readonly,
 
     /// Interpret `%`-prefixed special arguments, and auto-infer full access
@@ -37,10 +50,81 @@ pub enum MagicLevel {
     Auto,
 }
 
+/// The default for [`Pathbox::set_max_grants`].
+const DEFAULT_MAX_GRANTS: usize = 1 << 16;
+
+/// The default for [`Pathbox::set_max_path_bytes`].
+const DEFAULT_MAX_PATH_BYTES: usize = 64 << 20;
+
+/// The default for [`Pathbox::set_max_arg_bytes`].
+const DEFAULT_MAX_ARG_BYTES: usize = 128 << 10;
+
+/// The prefix of every internal filename.
+const GUEST_PREFIX: &str = "guest-path.";
+
+/// A function which returns a new inner stream for a [`Writer`].
+type MakeWriter = Box<dyn Fn() -> Box<dyn io::Write> + Send + Sync>;
+
+/// A function which interprets escapes that aren't built in.
+type EscapeHandler = Box<dyn Fn(&str) -> Result<EscapeResult, Error> + Send + Sync>;
+
+/// The result of a handler set with [`Pathbox::set_escape_handler`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EscapeResult {
+    /// Replace the argument with the given string verbatim.
+    Verbatim(String),
+
+    /// Grant the given path with the given access, and replace the argument
+    /// with its internal filename, as the built-in escapes like `%read:` do.
+    Grant(OsString, Access),
+
+    /// The escape isn't recognized, so processing the argument fails with an
+    /// error, as it does when there's no handler.
+    Unhandled,
+}
+
 /// A utility for isolating external paths from internal paths.
+///
+/// Temporary directories created for `%temp-dir:` arguments are removed,
+/// along with everything in them, when the `Pathbox` is dropped, so the
+/// `Pathbox` should outlive the program's use of them.
 pub struct Pathbox {
     magic_level: MagicLevel,
     grants: Vec<Grant>,
+    strict: bool,
+    stdout: Option<MakeWriter>,
+    stderr: Option<MakeWriter>,
+    output_separator: Option<char>,
+    escape_handler: Option<EscapeHandler>,
+    log_level: Level,
+    log_color: bool,
+    log_color_override: Option<bool>,
+    translate_var_names: bool,
+    translate_quoted_words: bool,
+    expand_env_vars: bool,
+    regular_files_only: bool,
+    heuristics: Heuristics,
+    partial_colon_lists: bool,
+    colon_paths_exist: bool,
+    max_grants: usize,
+    max_path_bytes: usize,
+    max_arg_bytes: usize,
+    path_bytes: usize,
+    create_mode: Option<u32>,
+    hide_extensions: bool,
+    forbid_absolute: bool,
+    limit_escapes: bool,
+    readable_tokens: bool,
+    readable_token_separator: char,
+    non_executable_extensions: &'static [&'static str],
+    next_token: usize,
+    glued_path_flags: &'static [&'static str],
+    path_flags: &'static [(&'static str, Access)],
+    pending_path_access: Option<Access>,
+    normalize_paths: bool,
+    normalize_dot_dot: bool,
+    dir_cache: Option<Mutex<HashMap<String, Arc<Dir>>>>,
+    temp_dirs: Vec<tempfile::TempDir>,
 }
 
 impl Pathbox {
@@ -49,17 +133,458 @@ impl Pathbox {
         Self {
             magic_level,
             grants: Vec::new(),
+            strict: false,
+            stdout: None,
+            stderr: None,
+            output_separator: None,
+            escape_handler: None,
+            log_level: Level::Trace,
+            log_color: false,
+            log_color_override: None,
+            translate_var_names: false,
+            translate_quoted_words: false,
+            expand_env_vars: false,
+            regular_files_only: false,
+            heuristics: Heuristics::new(),
+            partial_colon_lists: false,
+            colon_paths_exist: false,
+            max_grants: DEFAULT_MAX_GRANTS,
+            max_path_bytes: DEFAULT_MAX_PATH_BYTES,
+            max_arg_bytes: DEFAULT_MAX_ARG_BYTES,
+            path_bytes: 0,
+            create_mode: None,
+            hide_extensions: false,
+            forbid_absolute: false,
+            limit_escapes: false,
+            readable_tokens: false,
+            readable_token_separator: '-',
+            non_executable_extensions: Self::NON_EXECUTABLE_EXTENSIONS,
+            next_token: 0,
+            glued_path_flags: &[],
+            path_flags: &[],
+            pending_path_access: None,
+            normalize_paths: false,
+            normalize_dot_dot: false,
+            dir_cache: None,
+            temp_dirs: Vec::new(),
+        }
+    }
+
+    /// Set whether arguments which contain a path separator, but which are
+    /// rejected by the path heuristics, should be reported as errors instead
+    /// of being passed through verbatim.
+    ///
+    /// This helps catch typos like `foo./bar` when arguments are processed,
+    /// rather than when the program fails to open them. Arguments longer
+    /// than [`Pathbox::set_max_arg_bytes`] are reported as errors too. It
+    /// only affects magic levels which infer paths. The default is `false`.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Set whether environment variable names, and not just values, should
+    /// be translated when they look like paths.
+    ///
+    /// Names are only ever inferred to be paths; `%` escapes in names are
+    /// not interpreted. The default is `false`, which passes names through
+    /// verbatim.
+    pub fn set_translate_var_names(&mut self, translate: bool) {
+        self.translate_var_names = translate;
+    }
+
+    /// Set whether words within quoted strings embedded in an argument, as
+    /// in `--exec="cat /etc/hosts"`, should be translated when they look
+    /// like paths.
+    ///
+    /// This is for programs that take sub-commands as single arguments. The
+    /// text between each pair of matching `"` or `'` characters is split on
+    /// whitespace, and each word that looks like a path is translated, with
+    /// the rest of the argument kept as is. This is inherently heuristic:
+    /// quoting and escaping aren't interpreted the way a shell would
+    /// interpret them, paths containing whitespace are split into separate
+    /// words, and unquoted text isn't examined. The default is `false`. This
+    /// only affects magic levels which infer paths.
+    pub fn set_translate_quoted_words(&mut self, translate: bool) {
+        self.translate_quoted_words = translate;
+    }
+
+    /// Set whether references to environment variables, written `$VAR` or
+    /// `${VAR}`, should be expanded in arguments before inferring whether
+    /// they're paths, so that `$HOME/config` is granted as the user's
+    /// configuration directory.
+    ///
+    /// Variables are looked up in the host's environment, not the program's,
+    /// when arguments are processed, and an undefined variable is an error.
+    /// Arguments which aren't inferred to be paths are passed through
+    /// unexpanded, and escapes are never expanded. The default is `false`,
+    /// since expansion makes the access granted depend on the environment.
+    /// This only affects magic levels which infer paths.
+    pub fn set_expand_env_vars(&mut self, expand: bool) {
+        self.expand_env_vars = expand;
+    }
+
+    /// Set whether colon-separated lists which contain some parts that look
+    /// like paths and some that don't should have just the path-like parts
+    /// translated.
+    ///
+    /// By default, a colon-separated list is only translated if all of its
+    /// parts look like paths, and otherwise it's passed through verbatim, so
+    /// that `/a:/b` is translated but `/a:notapath` isn't. With this enabled,
+    /// `/a:notapath` has `/a` translated and `notapath` left as is. Note that
+    /// this also translates the path in strings like `hostname:/tmp`.
+    pub fn set_partial_colon_lists(&mut self, partial: bool) {
+        self.partial_colon_lists = partial;
+    }
+
+    /// Set whether an argument containing colons that names an existing file
+    /// or directory should be treated as a single path, rather than as a
+    /// colon-separated list.
+    ///
+    /// This requires checking the filesystem while processing arguments, so
+    /// it's disabled by default. The check is advisory: the filesystem may
+    /// change between the check and the eventual use of the path.
+    pub fn set_check_colon_paths_exist(&mut self, check: bool) {
+        self.colon_paths_exist = check;
+    }
+
+    /// Set the maximum number of grants, after which processing further paths
+    /// fails with an error.
+    ///
+    /// This limits the resources that an untrusted command line can consume.
+    /// The default is 65536.
+    pub fn set_max_grants(&mut self, max: usize) {
+        self.max_grants = max;
+    }
+
+    /// Set the maximum total length in bytes of the granted paths, after
+    /// which processing further paths fails with an error.
+    ///
+    /// The default is 64 MiB.
+    pub fn set_max_path_bytes(&mut self, max: usize) {
+        self.max_path_bytes = max;
+    }
+
+    /// Set the maximum length in bytes of an argument which is examined for
+    /// paths, after which it's passed through verbatim without being
+    /// examined, or in strict mode, reported as an error.
+    ///
+    /// This bounds the work done on each argument of an untrusted command
+    /// line. The path heuristics never recognize paths longer than 4096
+    /// bytes, but an argument such as a colon-separated list may contain
+    /// several paths, so this limit is higher. Escapes aren't affected. The
+    /// default is 128 KiB.
+    pub fn set_max_arg_bytes(&mut self, max: usize) {
+        self.max_arg_bytes = max;
+    }
+
+    /// Set the permissions mode for files created by [`Pathbox::create`],
+    /// [`Pathbox::append_create`], [`Pathbox::open_append_read`],
+    /// [`Pathbox::open_read_write_create`], and [`Pathbox::open_temp`], such
+    /// as `0o600` for files which should only
+    /// be accessible to their owner.
+    ///
+    /// The mode is subject to the process' umask, as usual. By default, the
+    /// platform's default mode is used. On platforms other than Unix, this
+    /// has no effect.
+    pub fn set_create_mode(&mut self, mode: u32) {
+        self.create_mode = Some(mode);
+    }
+
+    /// Set whether internal filenames should omit the extensions of the
+    /// paths they replace.
+    ///
+    /// By default, internal filenames keep the extension, so that programs
+    /// which look at extensions to determine file types keep working. When
+    /// even the file type is sensitive, this may be enabled so that internal
+    /// filenames reveal nothing about the paths they replace.
+    pub fn set_hide_extensions(&mut self, hide: bool) {
+        self.hide_extensions = hide;
+    }
+
+    /// Set whether inferred paths which are absolute should be reported as
+    /// errors instead of being granted.
+    ///
+    /// This restricts inferred paths to relative paths, which are typically
+    /// within the current directory. It doesn't affect paths given with `%`
+    /// escapes, which are always explicit. The default is `false`.
+    pub fn set_forbid_absolute(&mut self, forbid: bool) {
+        self.forbid_absolute = forbid;
+    }
+
+    /// Set whether escapes are limited to the kinds of access which the
+    /// magic level infers.
+    ///
+    /// By default, escapes can grant any access at any magic level, so at
+    /// [`MagicLevel::Readonly`], `%write:` still grants write access; this
+    /// lets programs infer read-only access for most paths while granting
+    /// write access explicitly. When enabled, escapes which would permit
+    /// modifications at `Readonly`, such as `%write:`, `%append:`, `%rwc:`,
+    /// `%mutable-dir:`, `%temp-dir:`, and `%mutable-cwd`, fail with an error
+    /// instead.
+    /// Other magic levels don't limit escapes.
+    pub fn set_limit_escapes(&mut self, limit: bool) {
+        self.limit_escapes = limit;
+    }
+
+    /// Set a list of short flags, such as `-I` and `-L`, which may have a
+    /// path glued on, as in `-I/usr/include`.
+    ///
+    /// When an argument starts with one of these flags and has more after
+    /// it, the remainder is treated as a path, even if the heuristics
+    /// wouldn't recognize it. By default, the list is empty, and since
+    /// arguments starting with `-` are assumed to be flags, such paths aren't
+    /// recognized. This only affects magic levels which infer paths.
+    pub fn set_glued_path_flags(&mut self, flags: &'static [&'static str]) {
+        self.glued_path_flags = flags;
+    }
+
+    /// Set a list of flags, such as `-o`, which are followed by a separate
+    /// argument that's a path, along with the access to grant to it, such as
+    /// `Write` for output files.
+    ///
+    /// The argument following one of these flags is treated as a path, even
+    /// if the heuristics wouldn't recognize it. The access is limited to what
+    /// the magic level would grant to an inferred path. By default, the list
+    /// is empty. This only affects magic levels which infer paths.
+    pub fn set_path_flags(&mut self, flags: &'static [(&'static str, Access)]) {
+        self.path_flags = flags;
+    }
+
+    /// Set whether paths should have redundant separators and `.` components
+    /// removed before they're granted, so that `foo//./bar` is stored as
+    /// `foo/bar`.
+    ///
+    /// A leading `//` is preserved, since it may have a special meaning, as
+    /// it does in Windows UNC paths. `..` components are preserved too,
+    /// unless [`Pathbox::set_normalize_dot_dot`] is enabled. The default is
+    /// `false`, which stores paths verbatim.
+    pub fn set_normalize_paths(&mut self, normalize: bool) {
+        self.normalize_paths = normalize;
+    }
+
+    /// Set whether normalizing paths, as enabled by
+    /// [`Pathbox::set_normalize_paths`], should also resolve `..` components
+    /// lexically, so that `foo/../bar` is stored as `bar`.
+    ///
+    /// This changes the meaning of paths which traverse symlinks, so it's
+    /// disabled by default.
+    pub fn set_normalize_dot_dot(&mut self, resolve: bool) {
+        self.normalize_dot_dot = resolve;
+    }
+
+    /// Set whether internal filenames should be made readable, using a
+    /// counter and the base name of the path they replace, such as
+    /// `guest-path.1-input.txt`, instead of a random UUID.
+    ///
+    /// This makes translated arguments easier to read when debugging, but
+    /// reveals the base names of paths to the program, so it's disabled by
+    /// default. Internal filenames are still unique, and still translated
+    /// and opened as usual.
+    pub fn set_readable_tokens(&mut self, readable: bool) {
+        self.readable_tokens = readable;
+    }
+
+    /// Set the character which separates the counter from the base name in
+    /// readable internal filenames, as enabled by
+    /// [`Pathbox::set_readable_tokens`]. The default is `-`.
+    ///
+    /// Base names are limited to ASCII alphanumerics, `_`, and `-`, with any
+    /// `.`s replaced, so the extension of a readable internal filename always
+    /// starts at its first `.` after the `guest-path.` prefix. See
+    /// [`Pathbox::split_guest_extension`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `separator` isn't ASCII punctuation, or is `.` or a path
+    /// separator.
+    pub fn set_readable_token_separator(&mut self, separator: char) {
+        assert!(
+            separator.is_ascii_punctuation() && separator != '.' && !is_separator(separator),
+            "invalid readable token separator {:?}",
+            separator
+        );
+        self.readable_token_separator = separator;
+    }
+
+    /// Split an internal filename into the part which identifies its grant
+    /// and its extension, which is empty if it has none.
+    ///
+    /// Unlike [`split_extension`], this skips over the `guest-path.` prefix,
+    /// so that `guest-path.1-input.tar.gz` is split into `guest-path.1-input`
+    /// and `.tar.gz`. Strings which aren't internal filenames have no
+    /// extension.
+    ///
+    /// [`split_extension`]: crate::split_extension
+    pub fn split_guest_extension(guest: &str) -> (&str, &str) {
+        let Some(rest) = guest.strip_prefix(GUEST_PREFIX) else {
+            return (guest, "");
+        };
+        match rest.find('.') {
+            Some(dot) => guest.split_at(GUEST_PREFIX.len() + dot),
+            None => (guest, ""),
         }
     }
 
+    /// A small set of extensions of files which are clearly not executable,
+    /// for use with [`Pathbox::set_non_executable_extensions`].
+    pub const NON_EXECUTABLE_EXTENSIONS: &'static [&'static str] = &[
+        "csv", "gif", "htm", "html", "jpeg", "jpg", "json", "log", "md", "pdf", "png", "rst",
+        "svg", "toml", "txt", "xml", "yaml", "yml",
+    ];
+
+    /// Set a list of extensions, without the leading `.`, of files which
+    /// should never be granted read/execute access, so that
+    /// `%read-exec:notes.txt` fails with an error.
+    ///
+    /// This guards against mistakes such as granting execute access to the
+    /// wrong argument. Extensions are compared case-insensitively. The
+    /// default is [`Pathbox::NON_EXECUTABLE_EXTENSIONS`]; an empty list
+    /// disables the check.
+    pub fn set_non_executable_extensions(&mut self, extensions: &'static [&'static str]) {
+        self.non_executable_extensions = extensions;
+    }
+
+    /// Set whether opening files is limited to regular files, so that
+    /// FIFOs, devices, and sockets can't be opened, even if they're granted.
+    ///
+    /// Opening such files can block, or have other surprising effects, so
+    /// hosts which must never let a program do so may enable this. When
+    /// enabled, the file is checked before it's opened, and opening
+    /// anything other than a regular file fails with `PermissionDenied`.
+    /// Since the check happens before opening, it doesn't protect against
+    /// the file being replaced in between. Standard input, granted with
+    /// `%read:-`, may still be opened. The default is `false`, which
+    /// permits opening any kind of file, like `File::open`.
+    pub fn set_regular_files_only(&mut self, regular_only: bool) {
+        self.regular_files_only = regular_only;
+    }
+
+    /// Set whether granted directories should be kept open once they've
+    /// been opened, so that repeatedly opening them, or files within them,
+    /// reuses one handle per directory instead of opening the directory
+    /// each time.
+    ///
+    /// This bounds the number of file descriptors used by directory-heavy
+    /// workloads, at the cost of keeping each directory that's been opened
+    /// open for the life of the `Pathbox`. Handles returned by
+    /// [`Pathbox::open_dir`] and similar are still independent handles to
+    /// the directory. The cache is guarded by a mutex, so `Pathbox` remains
+    /// usable from multiple threads, though concurrent opens briefly
+    /// contend on the lock. Since the cached handle refers to the directory
+    /// that was first opened, renaming or replacing the directory on the
+    /// host afterward isn't observed. The default is `false`, and disabling
+    /// it closes any cached handles.
+    pub fn set_cache_dirs(&mut self, cache: bool) {
+        self.dir_cache = cache.then(Default::default);
+    }
+
+    /// Set the configuration of the heuristics used to recognize paths when
+    /// the magic level infers them.
+    pub fn set_heuristics(&mut self, heuristics: Heuristics) {
+        self.heuristics = heuristics;
+    }
+
+    /// Set the minimum level of messages printed by [`Pathbox::log`].
+    ///
+    /// [`Level::Off`] disables logging entirely. The default is
+    /// [`Level::Trace`], which prints everything.
+    pub fn set_log_level(&mut self, level: Level) {
+        self.log_level = level;
+    }
+
+    /// Set whether messages printed by [`Pathbox::log`] should have their
+    /// levels colored with ANSI escape sequences, as is useful when the
+    /// standard error stream is a terminal.
+    ///
+    /// The default is `false`. [`Pathbox::set_log_color_from_env`] lets the
+    /// environment override this setting.
+    pub fn set_log_color(&mut self, color: bool) {
+        self.log_color = color;
+    }
+
+    /// Let the `NO_COLOR` and `CLICOLOR_FORCE` environment variables, if
+    /// they're currently set to non-empty values, take precedence over
+    /// [`Pathbox::set_log_color`], disabling or forcing colors respectively,
+    /// following the common conventions. If both are set, `NO_COLOR` wins.
+    ///
+    /// The environment isn't consulted unless this is called, so that a
+    /// `Pathbox`'s output doesn't otherwise depend on it.
+    pub fn set_log_color_from_env(&mut self) {
+        self.log_color_override = log::color_override(
+            std::env::var_os("NO_COLOR").as_deref(),
+            std::env::var_os("CLICOLOR_FORCE").as_deref(),
+        );
+    }
+
+    /// Set a function which interprets `%` escapes that aren't built in,
+    /// such as `%secret:name`, so that hosts can define their own escapes.
+    ///
+    /// The function is passed the argument after the leading `%`, and is
+    /// only consulted for escapes which aren't built in, so the built-in
+    /// escapes always take precedence. Grants it requests are subject to
+    /// the same checks as grants from the built-in escapes, such as
+    /// [`Pathbox::set_limit_escapes`].
+    pub fn set_escape_handler(
+        &mut self,
+        handler: impl Fn(&str) -> Result<EscapeResult, Error> + Send + Sync + 'static,
+    ) {
+        self.escape_handler = Some(Box::new(handler));
+    }
+
+    /// Set a function which returns the stream that [`Pathbox::stdout`]
+    /// writes translated output to, instead of the process' standard output.
+    ///
+    /// This is mainly useful for capturing output in tests.
+    pub fn set_stdout(&mut self, make: impl Fn() -> Box<dyn io::Write> + Send + Sync + 'static) {
+        self.stdout = Some(Box::new(make));
+    }
+
+    /// Set a function which returns the stream that [`Pathbox::stderr`] and
+    /// [`Pathbox::log`] write translated output to, instead of the process'
+    /// standard error.
+    ///
+    /// This is mainly useful for capturing output in tests.
+    pub fn set_stderr(&mut self, make: impl Fn() -> Box<dyn io::Write> + Send + Sync + 'static) {
+        self.stderr = Some(Box::new(make));
+    }
+
+    /// Set the path separator, `/` or `\`, which paths substituted for
+    /// internal filenames in output, such as by [`Pathbox::stdout`], should
+    /// use, so that they're consistent with the rest of the output.
+    ///
+    /// The default is `None`, which emits paths verbatim. On Unix, `\` is an
+    /// ordinary character in filenames, so this is mainly useful on Windows,
+    /// where both are separators.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `separator` is neither `/` nor `\`.
+    pub fn set_output_separator(&mut self, separator: Option<char>) {
+        assert!(
+            matches!(separator, None | Some('/' | '\\')),
+            "invalid output separator {:?}",
+            separator
+        );
+        self.output_separator = separator;
+    }
+
     /// Add the given command-line argument to the environment, and return a
     /// translated argument.
+    ///
+    /// This is for programs which receive arguments one at a time. It's
+    /// equivalent to calling [`Pathbox::process_args`] with a single
+    /// argument, and the two may be mixed freely.
     pub fn process_arg(&mut self, arg: String) -> Result<String, Error> {
         self.process(arg)
     }
 
     /// Add the given command-line arguments to the environment, and return a
     /// translated list of arguments.
+    ///
+    /// Internal filenames of this `Pathbox`'s grants, and paths within them,
+    /// are never inferred to be paths themselves, so processing arguments
+    /// which have already been translated leaves them unchanged. This makes
+    /// it safe to process arguments in several stages.
     pub fn process_args(
         &mut self,
         args: impl Iterator<Item = String>,
@@ -71,8 +596,29 @@ impl Pathbox {
         Ok(new_args)
     }
 
+    /// Like [`Pathbox::process_args`], but pair each translated argument
+    /// with the access it was translated with, or `None` if it was passed
+    /// through verbatim.
+    ///
+    /// This lets a program tell which arguments are paths without applying
+    /// the heuristics itself.
+    pub fn process_args_annotated(
+        &mut self,
+        args: impl Iterator<Item = String>,
+    ) -> Result<Vec<(String, Option<Access>)>, Error> {
+        let mut new_args = Vec::new();
+        for arg in args {
+            new_args.push(self.process_annotated(arg)?);
+        }
+        Ok(new_args)
+    }
+
     /// Add the given command-line argument to the environment, and return a
     /// translated argument.
+    ///
+    /// This is for programs which receive arguments one at a time. It's
+    /// equivalent to calling [`Pathbox::process_args_os`] with a single
+    /// argument, and the two may be mixed freely.
     pub fn process_arg_os(&mut self, arg: OsString) -> Result<String, Error> {
         self.process_os(arg)
     }
@@ -90,10 +636,29 @@ impl Pathbox {
         Ok(new_args)
     }
 
+    /// Like [`Pathbox::process_args_os`], but return the translated
+    /// arguments as `OsString`s.
+    ///
+    /// Translated arguments are always valid Unicode, so this is just a
+    /// convenience for code which uses `OsString`s throughout.
+    pub fn process_args_os_to_os(
+        &mut self,
+        args: impl Iterator<Item = OsString>,
+    ) -> Result<Vec<OsString>, Error> {
+        Ok(self
+            .process_args_os(args)?
+            .into_iter()
+            .map(OsString::from)
+            .collect())
+    }
+
     /// Add the given environment variable the environment, and return a
     /// translated environment variable.
     pub fn process_var(&mut self, env: (String, String)) -> Result<(String, String), Error> {
         let (key, val) = env;
+        // Flags in the arguments don't apply to environment variables.
+        self.pending_path_access = None;
+        let key = self.process_var_name(key)?;
         Ok((key, self.process(val)?))
     }
 
@@ -111,6 +676,25 @@ impl Pathbox {
         Ok(new_envs)
     }
 
+    /// Like [`Pathbox::process_vars`], but return the translated environment
+    /// variables in a map, for passing to APIs such as
+    /// `std::process::Command::envs`.
+    ///
+    /// If a variable appears more than once, the last value wins, as it
+    /// would when setting the variables in order. Earlier values are still
+    /// processed, so any paths in them are still granted.
+    pub fn process_vars_map(
+        &mut self,
+        envs: impl Iterator<Item = (String, String)>,
+    ) -> Result<HashMap<String, String>, Error> {
+        let mut new_envs = HashMap::new();
+        for env in envs {
+            let (key, val) = self.process_var(env)?;
+            new_envs.insert(key, val);
+        }
+        Ok(new_envs)
+    }
+
     /// Add the given environment variable the environment, and return a
     /// translated environment variable.
     pub fn process_var_os(&mut self, env: (OsString, OsString)) -> Result<(String, String), Error> {
@@ -124,941 +708,3964 @@ impl Pathbox {
                 )))
             }
         };
+        self.pending_path_access = None;
+        let key = self.process_var_name(key)?;
         Ok((key, self.process_os(val)?))
     }
 
     /// Add the given environment variables the environment, and return a
     /// translated list of environment variables.
+    ///
+    /// Unlike [`Pathbox::process_var_os`], variables with names that aren't
+    /// valid Unicode are skipped rather than reported as errors, since they
+    /// can't be represented in the result, and a single exotic variable in
+    /// the environment shouldn't prevent a program from running.
     pub fn process_vars_os(
         &mut self,
         envs: impl Iterator<Item = (OsString, OsString)>,
     ) -> Result<Vec<(String, String)>, Error> {
         let mut new_envs = Vec::new();
-        for env in envs {
-            let (key, val) = self.process_var_os(env)?;
+        for (key, val) in envs {
+            if key.to_str().is_none() {
+                continue;
+            }
+            let (key, val) = self.process_var_os((key, val))?;
             new_envs.push((key, val));
         }
         Ok(new_envs)
     }
 
-    /// Open a file given an internal filename.
+    /// Construct a new instance of `Pathbox` with the grants stored in the
+    /// environment variable `var_name` by [`Pathbox::to_env_value`].
     ///
-    /// This function does no actual I/O; it just looks up the path and
-    /// returns the translated path that can be opened with ambient
-    /// authority.
-    pub fn host_path(&self, path: &str, access: Access) -> io::Result<PathBuf> {
-        for grant in &self.grants {
-            if grant.access != Access::Any && !grant.access.includes(access) {
-                continue;
-            }
-            if let Some(rest) = path.strip_prefix(&grant.guest) {
-                let mut path = grant.original.clone();
-                path.push(rest);
-                return Ok(path.into());
+    /// This allows a parent process to pass its grants on to a child process
+    /// which also uses `Pathbox`, conventionally in a variable named
+    /// `PATHBOX_PREOPENS`.
+    pub fn from_env(var_name: &str, magic_level: MagicLevel) -> Result<Self, Error> {
+        let value = std::env::var(var_name).map_err(|err| {
+            Error(format!(
+                "Failed to read environment variable {:?}: {}",
+                var_name, err
+            ))
+        })?;
+
+        let mut pathbox = Self::new(magic_level);
+        for (index, line) in value.lines().enumerate() {
+            let malformed = |what: &str| {
+                Error(format!(
+                    "Malformed grant on line {} of environment variable {:?}: {}",
+                    index + 1,
+                    var_name,
+                    what
+                ))
+            };
+            let mut fields = line.splitn(3, ' ');
+            let (access, guest, original) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(access), Some(guest), Some(original)) => (access, guest, original),
+                _ => return Err(malformed("expected three fields")),
+            };
+            // A byte range, if any, is appended to the access name, as in
+            // `read:0-100`.
+            let (access, range) = match access.split_once(':') {
+                Some((access, range)) => {
+                    let (start, end) = range
+                        .split_once('-')
+                        .and_then(|(start, end)| Some((start.parse().ok()?, end.parse().ok()?)))
+                        .filter(|(start, end)| start <= end)
+                        .ok_or_else(|| malformed("invalid byte range"))?;
+                    (access, Some(start..end))
+                }
+                None => (access, None),
+            };
+            let stdin = access == "stdin";
+            let access = if stdin {
+                Access::Read
+            } else {
+                Access::from_name(access).ok_or_else(|| malformed("unrecognized access"))?
+            };
+            if !guest.starts_with(GUEST_PREFIX) {
+                return Err(malformed("unrecognized guest path"));
             }
+            let original = decode_original(original)
+                .ok_or_else(|| malformed("invalid encoding of original path"))?;
+            pathbox.path_bytes = pathbox.path_bytes.saturating_add(original.len());
+            pathbox.grants.push(Grant {
+                guest: guest.to_owned(),
+                original,
+                access,
+                range,
+                stdin,
+            });
         }
+        Ok(pathbox)
+    }
 
-        Err(self.search_failed(path))
+    /// Return the number of grants.
+    pub fn num_grants(&self) -> usize {
+        self.grants.len()
     }
 
-    /// Open a file given an internal filename.
-    pub fn open(&self, path: &str) -> io::Result<File> {
-        let full_path = self.host_path(path, Access::Read)?;
-        File::open_ambient(&full_path, ambient_authority())
+    /// Test whether no grants have been made.
+    pub fn is_empty(&self) -> bool {
+        self.grants.is_empty()
     }
 
-    /// Create a file given an internal filename.
-    pub fn create(&self, path: &str) -> io::Result<File> {
-        let full_path = self.host_path(path, Access::Write)?;
-        File::create_ambient(&full_path, ambient_authority())
+    /// Iterate over the grants, in the order they were made.
+    ///
+    /// Each grant appears at the position where its path was first
+    /// encountered. When a path is granted again, the existing grant's
+    /// access is widened in place, so grants never move, and hosts may rely
+    /// on their positions. [`Pathbox::to_env_value`] and
+    /// [`Pathbox::from_env`] preserve this order too.
+    ///
+    /// For a view of the grants which doesn't depend on the fields of
+    /// [`Grant`], use [`Pathbox::preopens`].
+    pub fn grants(&self) -> impl ExactSizeIterator<Item = &Grant> {
+        self.grants.iter()
     }
 
-    /// Open a file for appending given an internal filename.
-    pub fn append(&self, path: &str) -> io::Result<File> {
-        let full_path = self.host_path(path, Access::Append)?;
-        File::open_ambient_with(
-            &full_path,
-            OpenOptions::new().append(true),
-            ambient_authority(),
-        )
+    /// Iterate over the grants, in the same order as [`Pathbox::grants`], as
+    /// [`PreopenInfo`]s.
+    ///
+    /// For example, to print all the grants:
+    ///
+    /// ```
+    /// use pathbox::{MagicLevel, Pathbox};
+    ///
+    /// let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    /// let args = ["%read:in.txt", "%write:out.txt"].map(str::to_owned);
+    /// pathbox.process_args(args.into_iter()).unwrap();
+    ///
+    /// for preopen in pathbox.preopens() {
+    ///     println!(
+    ///         "{} {:?} {:?}",
+    ///         preopen.token(),
+    ///         preopen.original(),
+    ///         preopen.access()
+    ///     );
+    /// }
+    /// ```
+    pub fn preopens(&self) -> impl ExactSizeIterator<Item = PreopenInfo<'_>> {
+        self.grants.iter().map(|grant| PreopenInfo { grant })
     }
 
-    /// Open a directory given an internal filename.
-    pub fn open_dir(&self, path: &str) -> io::Result<DirView> {
-        let full_path = self.host_path(path, Access::ReadonlyDir)?;
-        DirView::open_ambient_dir(&full_path, ViewKind::Readonly, ambient_authority())
+    /// Consume the `Pathbox` and return its grants, in the same order as
+    /// [`Pathbox::grants`], without cloning them.
+    ///
+    /// This is useful for handing the grants off to another subsystem once
+    /// the `Pathbox` itself is no longer needed. Temporary directories
+    /// created by `%temp-dir:` are kept, rather than removed, so that the
+    /// grants remain usable; removing them is then up to the caller.
+    pub fn into_grants(mut self) -> Vec<Grant> {
+        for temp_dir in self.temp_dirs.drain(..) {
+            let _path = temp_dir.keep();
+        }
+        std::mem::take(&mut self.grants)
     }
 
-    /// Open a mutable directory given an internal filename.
-    pub fn open_mutable_dir(&self, path: &str) -> io::Result<DirView> {
-        let full_path = self.host_path(path, Access::MutableDir)?;
-        DirView::open_ambient_dir(&full_path, ViewKind::Full, ambient_authority())
+    /// Return the internal filename of an existing grant of `original`, or
+    /// `None` if it hasn't been granted.
+    ///
+    /// This doesn't create a grant, and the comparison is an exact
+    /// comparison of the `OsStr`s, so it works for non-UTF-8 paths. Grants
+    /// restricted to a byte range are not considered.
+    pub fn canonical_token(&self, original: impl AsRef<OsStr>) -> Option<String> {
+        let original = original.as_ref();
+        self.grants
+            .iter()
+            .find(|g| g.original == original && g.range.is_none() && !g.stdin)
+            .map(|g| g.guest.clone())
+    }
+
+    /// Widen the access of the grant with internal filename `token` to
+    /// include `access`.
+    ///
+    /// The new access is the least upper bound of the old access and
+    /// `access`, as described in [`Access::merge`], so this never removes
+    /// access, and fails if there isn't one. Grants of standard input and
+    /// grants restricted to a byte range can't be upgraded.
+    pub fn upgrade(&mut self, token: &str, access: Access) -> Result<(), Error> {
+        let grant = self
+            .grants
+            .iter_mut()
+            .find(|g| g.guest == token)
+            .ok_or_else(|| Error(format!("{:?} is not granted", token)))?;
+        check_executable(&grant.original, access, self.non_executable_extensions)?;
+        let merged = grant
+            .access
+            .merge(access)
+            .ok_or_else(|| incompatible_access(grant, access))?;
+        if merged != grant.access && (grant.stdin || grant.range.is_some()) {
+            return Err(Error(format!(
+                "Grant {:?} is restricted, and can't be upgraded to {:?} access",
+                token, access
+            )));
+        }
+        grant.access = merged;
+        Ok(())
     }
 
-    fn search_failed(&self, path: &str) -> io::Error {
-        // Attempt to provide a more detailed error message.
+    /// Translate an external path into an internal filename, if it's granted
+    /// or is within a granted directory.
+    ///
+    /// When granted directories are nested, the most specific one is used,
+    /// so that if `/a` and `/a/b` are both granted, `/a/b/c` is translated
+    /// relative to `/a/b`. This doesn't create any grants.
+    pub fn translate_in(&self, path: impl AsRef<OsStr>) -> Option<String> {
+        let path = std::path::Path::new(path.as_ref());
+        let mut best: Option<(&Grant, &std::path::Path)> = None;
         for grant in &self.grants {
-            let access = match grant.access {
-                Access::Read => "read",
-                Access::Write => "write",
-                Access::Append => "append",
-                Access::ReadonlyDir => "readonly directory",
-                Access::MutableDir => "read/write directory",
-                Access::Any => continue,
+            if grant.stdin {
+                continue;
+            }
+            let rest = match path.strip_prefix(&grant.original) {
+                Ok(rest) => rest,
+                Err(_) => continue,
             };
-            if let Some(_rest) = path.strip_prefix(&grant.guest) {
-                return io::Error::new(
-                    io::ErrorKind::PermissionDenied,
-                    format!("Grant '{:?}' only permits {:?} access", grant.guest, access),
-                );
+            // Only directories have paths within them.
+            let is_dir = matches!(
+                grant.access,
+                Access::ReadonlyDir | Access::MutableDir | Access::Any
+            );
+            if !rest.as_os_str().is_empty() && (!is_dir || grant.range.is_some()) {
+                continue;
+            }
+            // Prefer the longest, and therefore most specific, original.
+            if best.is_none_or(|(best, _)| grant.original.len() > best.original.len()) {
+                best = Some((grant, rest));
             }
         }
 
-        io::Error::new(io::ErrorKind::PermissionDenied, "File is not available")
+        let (grant, rest) = best?;
+        if rest.as_os_str().is_empty() {
+            return Some(grant.guest.clone());
+        }
+        Some(format!("{}/{}", grant.guest, rest.to_str()?))
     }
 
-    /// Return a standard-output stream which translates any internal filenames
-    /// written to it into external filenames.
-    pub fn stdout(&self) -> Writer<'_> {
-        crate::writer::stdout(self)
+    /// Check the grants for problems which would otherwise only surface as
+    /// confusing failures when they're used, and return all of the problems
+    /// found.
+    ///
+    /// This is mainly useful for grants read with [`Pathbox::from_env`],
+    /// or made with escapes like `%read:` with an empty path.
+    pub fn validate(&self) -> Result<(), Vec<Error>> {
+        let mut errors = Vec::new();
+        for (index, grant) in self.grants.iter().enumerate() {
+            if grant.original.is_empty() {
+                errors.push(Error(format!("Grant {:?} has an empty path", grant.guest)));
+            }
+            #[cfg(unix)]
+            if grant.original.as_bytes().contains(&0) {
+                errors.push(Error(format!(
+                    "Grant {:?} has a path containing NUL: {:?}",
+                    grant.guest, grant.original
+                )));
+            }
+            if !grant.guest.starts_with(GUEST_PREFIX) {
+                errors.push(Error(format!(
+                    "Grant {:?} has an unrecognized guest path",
+                    grant.guest
+                )));
+            }
+            if grant.range.is_some() && grant.access != Access::Read {
+                errors.push(Error(format!(
+                    "Grant {:?} has a byte range but isn't readonly",
+                    grant.guest
+                )));
+            }
+            // Guest paths are matched by prefix, so one guest path being a
+            // prefix of another would make the longer one unreachable.
+            for other in &self.grants[..index] {
+                if grant.guest.starts_with(&other.guest) || other.guest.starts_with(&grant.guest) {
+                    errors.push(Error(format!(
+                        "Grants {:?} and {:?} have overlapping guest paths",
+                        other.guest, grant.guest
+                    )));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
     }
 
-    /// Return a standard-error stream which translates any internal filenames
-    /// written to it into external filenames.
-    pub fn stderr(&self) -> Writer<'_> {
-        crate::writer::stderr(self)
+    /// Serialize the grants into a string which can be stored in an
+    /// environment variable and read back with [`Pathbox::from_env`].
+    pub fn to_env_value(&self) -> String {
+        let mut value = String::new();
+        for grant in &self.grants {
+            if !value.is_empty() {
+                value.push('\n');
+            }
+            // Standard input has its own access name, since its `original`
+            // isn't a path.
+            value.push_str(if grant.stdin {
+                "stdin"
+            } else {
+                grant.access.name()
+            });
+            if let Some(range) = &grant.range {
+                value.push_str(&format!(":{}-{}", range.start, range.end));
+            }
+            value.push(' ');
+            value.push_str(&grant.guest);
+            value.push(' ');
+            encode_original(&grant.original, &mut value);
+        }
+        value
     }
 
-    /// Print a log message which translatesa any internal filenames written
-    /// to it into external filenames.
-    pub fn log(&self, level: Level, context: &str, message: &str) {
-        log(&mut self.stderr(), level, context, message)
+    /// Translate an internal filename into the host path it refers to.
+    ///
+    /// This function does no actual I/O; it just looks up the path and
+    /// returns the translated path that can be opened with ambient
+    /// authority. This makes it useful for testing which accesses a set of
+    /// arguments grants without touching the filesystem. With the `mem-fs`
+    /// feature, `MemFs` uses this to open files in memory.
+    ///
+    /// If `path` isn't granted, this fails with `NotFound`, and if it's
+    /// granted but not with `access`, it fails with `PermissionDenied`. The
+    /// other functions which open files report errors the same way.
+    ///
+    /// `path` matches a grant if it's the grant's internal filename, or that
+    /// followed by a separator and a path within it. If it matches several
+    /// grants, which can only happen when internal filenames are chosen by
+    /// hand, the grant with the longest internal filename is used,
+    /// regardless of the order of the grants. The other functions which
+    /// open files look up grants the same way.
+    ///
+    /// Grants restricted to a byte range can't be resolved this way, since
+    /// the caller could access bytes outside of the range; use
+    /// [`Pathbox::open_range`] for those.
+    pub fn host_path(&self, path: &str, access: Access) -> io::Result<PathBuf> {
+        self.resolve(path, access).map(Cow::into_owned)
     }
 
-    /// Replace any paths in `arg` with random UUIDs, and populate `self`
-    /// with information about the replacements.
-    fn process_os(&mut self, arg: OsString) -> Result<String, Error> {
-        match arg.into_string() {
-            // If it's valid Unicode, apply the normal processing rules.
-            Ok(s) => self.process(s),
+    /// Like [`Pathbox::host_path`], but avoid allocating when `path` is a
+    /// whole grant, which is the common case.
+    fn resolve(&self, path: &str, access: Access) -> io::Result<Cow<'_, Path>> {
+        let (grant, full_path) = self.find_grant(path, access)?;
+        if grant.stdin {
+            return Err(stdin_has_no_path(grant));
+        }
+        if let Some(range) = &grant.range {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Grant '{:?}' only permits access to bytes {}..{}",
+                    grant.guest, range.start, range.end
+                ),
+            ));
+        }
+        Ok(full_path)
+    }
 
-            // Interpret any ill-formed string as a filename path, because
-            // why else would there be an ill-formed command-line or
-            // environment variable string?
-            #[cfg(unix)]
-            Err(s) => {
-                let default_access = match self.magic_level {
-                    MagicLevel::Auto => Access::Any,
-                    MagicLevel::Readonly => Access::Read,
-                    MagicLevel::Escapes | MagicLevel::None => {
-                        return Err(Error(
-                            "ill-formed strings require a greater magic level".to_owned(),
-                        ))
-                    }
-                };
-                Ok(self.replace_os_with_uuid(&s, default_access))
-            }
+    /// Look up the grant for an internal filename, and return it along with
+    /// the translated path.
+    ///
+    /// The translated path is formed by joining the remainder of the
+    /// filename onto the grant's host path, so remainders with `..`
+    /// components, which could escape the grant, fail with
+    /// `PermissionDenied`.
+    fn find_grant(&self, path: &str, access: Access) -> io::Result<(&Grant, Cow<'_, Path>)> {
+        let (grant, rest) = self.find_grant_rest(path, access)?;
+        if rest.is_empty() {
+            return Ok((grant, Cow::Borrowed(Path::new(&grant.original))));
+        }
+        if rest.split(is_separator).any(|component| component == "..") {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("'{:?}' contains '..', which may not follow a grant", path),
+            ));
+        }
+        let mut path = grant.original.clone();
+        path.push(rest);
+        Ok((grant, Cow::Owned(path.into())))
+    }
 
-            #[cfg(not(unix))]
-            Err(_) => Err(Error("ill-formed strings are not permitted".to_owned())),
+    /// Look up the grant for an internal filename, and return it along with
+    /// the remainder of the filename after the grant's guest path.
+    ///
+    /// A grant matches if the filename is its guest path, or its guest path
+    /// followed by a separator and a path within it. If several grants
+    /// match, which is only possible if guest paths have been chosen by
+    /// hand, the one with the longest guest path is used, so the result
+    /// doesn't depend on the order of the grants. If that grant doesn't
+    /// permit `access`, this fails with `PermissionDenied`, rather than
+    /// falling back to another grant, so that a filename always refers to
+    /// the same grant.
+    ///
+    /// Filenames which can't be internal filenames fail without searching
+    /// the grants.
+    fn find_grant_rest<'p>(&self, path: &'p str, access: Access) -> io::Result<(&Grant, &'p str)> {
+        if !has_guest_shape(path) {
+            return Err(not_available());
+        }
+        let (grant, rest) = self
+            .grants
+            .iter()
+            .filter_map(|grant| Some((grant, match_grant(grant, path)?)))
+            .fold(None, longest_match)
+            .ok_or_else(not_available)?;
+        if !grant.access.includes(access) {
+            return Err(access_denied(grant));
         }
+        Ok((grant, rest))
     }
 
-    /// Replace any paths in `arg` with random UUIDs, and populate `self`
-    /// with information about the replacements.
-    fn process(&mut self, arg: String) -> Result<String, Error> {
-        // Leading '%' is an escape to allow for special features.
-        if self.magic_level >= MagicLevel::Escapes {
-            if let Some(rest) = arg.strip_prefix('%') {
-                // `%verbatim:` means the remainder is a verbatim string.
-                if let Some(verbatim) = rest.strip_prefix("verbatim:") {
-                    return Ok(verbatim.to_owned());
-                }
-                // `%read:` means the remainder is a file that may be opened for reading.
-                if let Some(path) = rest.strip_prefix("read:") {
-                    return Ok(self.replace_with_uuid(path, Access::Read));
-                }
-                // `%write:` means the remainder is a file that may be opened for writing,
-                // creating, and truncating.
-                if let Some(path) = rest.strip_prefix("write:") {
-                    return Ok(self.replace_with_uuid(path, Access::Write));
-                }
-                // `%append:` means the remainder is a file that may be opened for appending.
-                if let Some(path) = rest.strip_prefix("append:") {
-                    return Ok(self.replace_with_uuid(path, Access::Append));
-                }
-                // `%dir:` means the remainder is a read-only directory.
-                if let Some(path) = rest.strip_prefix("dir:") {
-                    return Ok(self.replace_with_uuid(path, Access::ReadonlyDir));
-                }
-                // `%mutable-dir:` means the remainder is a mutable directory.
-                if let Some(path) = rest.strip_prefix("mutable-dir:") {
-                    return Ok(self.replace_with_uuid(path, Access::MutableDir));
-                }
-
-                return Err(Error("Arguments beginning with '%' have special meanings. Prepend \"%verbatim:\" to pass a verbatim argument through.".to_owned()));
-            }
-
-            if self.magic_level >= MagicLevel::Readonly {
-                let default_access = if self.magic_level >= MagicLevel::Auto {
-                    Access::Any
-                } else {
-                    Access::Read
-                };
-
-                if arg.contains(':') {
-                    // If all the parts between ':'s look like paths, interpret the
-                    // argument as a colon-separated list of paths.
-                    if arg.split(':').all(is_likely_path) {
-                        return Ok(arg
-                            .split(':')
-                            .map(|part| self.replace_with_uuid(part, default_access))
-                            .collect::<Vec<_>>()
-                            .join(":"));
-                    }
-
-                    return Ok(arg.to_owned());
-                }
+    /// If an internal filename names a granted directory or something
+    /// inside one, open the directory, and return it along with the
+    /// relative path within it, which is empty for the directory itself.
+    ///
+    /// Opening relative to the directory, rather than joining the paths,
+    /// ensures that `..` and symlinks can't be used to escape it.
+    pub(crate) fn find_in_dir<'p>(
+        &self,
+        path: &'p str,
+        access: Access,
+    ) -> io::Result<Option<(Arc<Dir>, &'p str)>> {
+        let (grant, rest) = self.find_grant_rest(path, access)?;
+        let rest = if rest.is_empty() {
+            rest
+        } else if let Some(rest) = rest.strip_prefix(is_separator) {
+            rest
+        } else {
+            return Ok(None);
+        };
+        let is_dir = match grant.access {
+            Access::ReadonlyDir | Access::MutableDir => true,
+            Access::Any => Path::new(&grant.original).is_dir(),
+            _ => false,
+        };
+        if !is_dir || grant.stdin || grant.range.is_some() {
+            return Ok(None);
+        }
+        let dir = self.open_grant_dir(grant)?;
+        Ok(Some((dir, rest.trim_start_matches(is_separator))))
+    }
 
-                if let Some(eq) = arg.find('=') {
-                    let (prefix, suffix) = arg.split_at(eq + 1);
-                    if !prefix.contains('/') && is_likely_path(suffix) {
-                        // No slash before the '=' and a slash after; treat it as
-                        // a `--input=/path/to/file.txt` case and replace the path part.
-                        let path = self.replace_with_uuid(suffix, default_access);
-                        return Ok(prefix.to_owned() + &path);
-                    }
-                }
+    /// Open the directory granted by `grant`, or reuse a cached handle to
+    /// it if [`Pathbox::set_cache_dirs`] is enabled.
+    fn open_grant_dir(&self, grant: &Grant) -> io::Result<Arc<Dir>> {
+        let open = || {
+            Dir::open_ambient_dir(&grant.original, ambient_authority())
+                .map_err(|err| hide_host_path(err, Path::new(&grant.original), &grant.guest))
+        };
+        let Some(cache) = &self.dir_cache else {
+            return Ok(Arc::new(open()?));
+        };
+        let mut cache = cache.lock().unwrap();
+        if let Some(dir) = cache.get(&grant.guest) {
+            return Ok(dir.clone());
+        }
+        let dir = Arc::new(open()?);
+        cache.insert(grant.guest.clone(), dir.clone());
+        Ok(dir)
+    }
 
-                if is_likely_path(&arg) {
-                    return Ok(self.replace_with_uuid(&arg, default_access));
+    /// Open a file given an internal filename, with `options`, requiring
+    /// `access`.
+    fn open_file(&self, path: &str, access: Access, options: &OpenOptions) -> io::Result<File> {
+        if let Some((dir, rest)) = self.find_in_dir(path, access)? {
+            if !rest.is_empty() {
+                if self.regular_files_only {
+                    check_regular(path, dir.metadata(rest).map(|m| m.is_file()))?;
                 }
+                return retry_on_interrupt(|| dir.open_with(rest, options));
             }
         }
-
-        Ok(arg.to_owned())
+        let full_path = self.resolve(path, access)?;
+        self.open_host_file(path, &full_path, options)
     }
 
-    fn replace_with_uuid(&mut self, s: &str, access: Access) -> String {
-        let (_base, ext) = split_extension(s);
-
-        let guest = format!("guest-path.{}{}", uuid::Uuid::new_v4(), ext);
-        let grant = Grant {
-            guest: guest.clone(),
-            original: s.to_owned().into(),
-            access,
-        };
-        self.grants.push(grant);
-        guest
+    /// Open a file given its host path, `full_path`, with `options`. `path`
+    /// is the internal filename, for use in errors.
+    fn open_host_file(
+        &self,
+        path: &str,
+        full_path: &Path,
+        options: &OpenOptions,
+    ) -> io::Result<File> {
+        if self.regular_files_only {
+            check_regular(path, std::fs::metadata(full_path).map(|m| m.is_file()))?;
+        }
+        retry_on_interrupt(|| File::open_ambient_with(full_path, options, ambient_authority()))
+            .map_err(|err| hide_host_path(err, full_path, path))
     }
 
-    #[cfg(unix)]
-    fn replace_os_with_uuid(&mut self, s: &OsStr, access: Access) -> String {
-        let guest = format!("guest-path.{}", uuid::Uuid::new_v4());
-        let grant = Grant {
-            guest: guest.clone(),
-            original: s.to_owned(),
-            access,
-        };
-        self.grants.push(grant);
-        guest
+    /// Open a directory given an internal filename, requiring `access`.
+    fn open_dir_with(&self, path: &str, access: Access) -> io::Result<Dir> {
+        if let Some((dir, rest)) = self.find_in_dir(path, access)? {
+            return if rest.is_empty() {
+                // Return an independent handle, unless this is the only one.
+                Arc::try_unwrap(dir).or_else(|dir| dir.try_clone())
+            } else {
+                dir.open_dir(rest)
+            };
+        }
+        let full_path = self.resolve(path, access)?;
+        Dir::open_ambient_dir(&full_path, ambient_authority())
+            .map_err(|err| hide_host_path(err, &full_path, path))
     }
 
-    pub(crate) fn as_slice(&self) -> &[Grant] {
-        &self.grants
+    /// Test whether the file or directory named by an internal filename
+    /// exists.
+    ///
+    /// This requires read or readonly directory access. If the path isn't
+    /// granted with such access, this returns `false` rather than revealing
+    /// whether it exists.
+    ///
+    /// Paths within granted directories are resolved relative to the
+    /// directory, so this doesn't reveal whether files outside of it exist.
+    pub fn exists(&self, path: &str) -> bool {
+        [Access::Read, Access::ReadonlyDir]
+            .into_iter()
+            .any(|access| match self.find_in_dir(path, access) {
+                Ok(Some((dir, rest))) => rest.is_empty() || dir.exists(rest),
+                Ok(None) => self
+                    .resolve(path, access)
+                    .is_ok_and(|full_path| full_path.exists()),
+                Err(_) => false,
+            })
     }
-}
 
-#[derive(Clone, Eq, PartialEq)]
-pub struct Error(String);
-
-impl std::error::Error for Error {}
-
-impl std::fmt::Debug for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        std::fmt::Display::fmt(self, f)
+    /// Open a file given an internal filename.
+    ///
+    /// If the internal filename refers to standard input, as with `%read:-`,
+    /// this returns a new handle to standard input. Standard input can only
+    /// be read once, and all handles to it share the same stream.
+    ///
+    /// If opening the file is interrupted by a signal, it's retried, as it
+    /// is for the other methods which open files, and for
+    /// [`fs::rename`] and [`fs::remove_file`].
+    ///
+    /// If an error from opening the file mentions its host path, the host
+    /// path is replaced with the internal filename, so that the program
+    /// can print errors without revealing where files are.
+    ///
+    /// A `File` can read any part of the file, so files granted with a byte
+    /// range, as with `%read-range:`, can't be opened this way, and this
+    /// fails with `PermissionDenied` for them. Use [`Pathbox::open_range`],
+    /// which opens files with or without a byte range, in programs which
+    /// accept ranged grants.
+    ///
+    /// [`fs::rename`]: crate::fs::rename
+    /// [`fs::remove_file`]: crate::fs::remove_file
+    pub fn open(&self, path: &str) -> io::Result<File> {
+        if let Ok((grant, _)) = self.find_grant(path, Access::Read) {
+            if grant.stdin {
+                return dup_stdin();
+            }
+        }
+        self.open_file(path, Access::Read, OpenOptions::new().read(true))
     }
-}
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        self.0.fmt(f)
+    /// Like [`Pathbox::open`], but also return the host path which was
+    /// opened.
+    ///
+    /// This is meant for host-side diagnostics, such as logging which file
+    /// an internal filename refers to. The path should never be shown to the
+    /// program itself. Standard input has no path, so it can't be opened
+    /// this way.
+    pub fn open_resolved(&self, path: &str) -> io::Result<(File, PathBuf)> {
+        let full_path = self.host_path(path, Access::Read)?;
+        let file = self.open_file(path, Access::Read, OpenOptions::new().read(true))?;
+        Ok((file, full_path))
     }
-}
-
-/// A record of a name which has been replaced.
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub struct Grant {
-    /// The replacement string to present to the guest.
-    pub guest: String,
-
-    /// The original string to use in the host.
-    pub original: OsString,
 
-    /// How the file may be accessed.
-    pub access: Access,
-}
-
-/// What types of file access should be permitted?
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum Access {
-    /// Allow read-only operations.
-    Read,
-    /// Allow writing, creating, and truncating files.
-    Write,
-    /// Allow appending to files.
-    Append,
-    /// Allow read-only directory operations.
-    ReadonlyDir,
-    /// Allow all directory operations.
-    MutableDir,
-    /// Allow any access to files.
-    Any,
-}
-
-impl Access {
-    pub fn includes(&self, other: Self) -> bool {
-        *self == other || (*self == Access::MutableDir && other == Access::ReadonlyDir)
+    /// Open a script for reading given an internal filename, so that an
+    /// interpreter can execute it.
+    ///
+    /// This requires `ReadExec` access, as granted by `%read-exec:`, rather
+    /// than just `Read` access.
+    pub fn open_executable(&self, path: &str) -> io::Result<File> {
+        self.open_file(path, Access::ReadExec, OpenOptions::new().read(true))
     }
-}
 
-/// If `s` is a path ending with a basename extension, split it into the
-/// path without the extension, and the extension.
-fn split_extension(s: &str) -> (&str, &str) {
-    let after_last_slash = match s.rfind('/') {
-        Some(slash) => slash + 1,
-        None => 0,
-    };
-    let basename = &s[after_last_slash..];
-
-    // If there are no embedded '.'s in the basename, there's no extension.
-    if !basename.contains('.') {
-        return (s, "");
+    /// Map a file into memory for reading, given an internal filename.
+    ///
+    /// This requires read access, as [`Pathbox::open`] does, and avoids
+    /// copying large inputs through reads.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated while it's mapped, by
+    /// this process or any other, as that would change memory which Rust
+    /// assumes is immutable.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn open_mmap(&self, path: &str) -> io::Result<memmap2::Mmap> {
+        let file = self.open(path)?;
+        // SAFETY: The caller guarantees that the file isn't modified while
+        // it's mapped.
+        unsafe { memmap2::Mmap::map(&file) }
     }
 
-    // The extension is the substring after the first dot which is not:
-    //  - at the beginning of the basename,
-    //  - immediately followed by another dot, or
-    //  - followed by a USV which is never an extension USV.
-    let mut remainder = &basename[1..];
-    while let Some(last_dot) = remainder.find('.') {
-        let ext = &remainder[last_dot + 1..];
-        if !ext.starts_with('.') && !ext.chars().any(is_never_extension) {
-            let suffix = &remainder[last_dot..];
-            return (&s[..s.len() - suffix.len()], suffix);
+    /// Open a new handle for reading a file which was granted as `token`.
+    ///
+    /// Unlike [`Pathbox::open`], `token` must be exactly the internal
+    /// filename of a grant, rather than a path within a granted directory.
+    /// Each call opens the file again, so the handle has its own offset,
+    /// except for standard input, where all handles share the one stream.
+    /// If `token` is no longer granted, this fails with `NotFound`.
+    pub fn reopen(&self, token: &str) -> io::Result<File> {
+        if !self.grants.iter().any(|g| g.guest == token) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("'{:?}' is not granted; it may have been revoked", token),
+            ));
         }
-        remainder = ext;
+        self.open(token)
     }
 
-    // Otherwise, there is no extension.
-    (s, "")
-}
-
-/// Test whether `c` is a `char` which is never part of a filename extension.
-fn is_never_extension(c: char) -> bool {
-    // These should be excluded already.
-    assert_ne!(c, '/');
-
-    // The following characters should never be permitted as a filename
-    // extension.
-    c.is_whitespace() || c.is_control()
-        // Selected ASCII codes which would be trouble an extension and as such
-        // are very unlikely to be an extension in the wild.
-        || c == '*'
-        || c == '"'
-        || c == '\''
-        || c == '`'
-        || c == ':'
-        || c == ';'
-        || c == '\\'
-        || c == '('
-        || c == ')'
-        || c == '{'
-        || c == '}'
-        || c == '['
-        || c == ']'
-        || c == '|'
-        || c == '>'
-        || c == '<'
-        // Just... no.
-        || c == '\u{feff}' // BOM
-        // Unicode specials are special.
-        || c == '\u{fff9}' // Interlinear annotations
-        || c == '\u{fffa}'
-        || c == '\u{fffb}'
-        || c == '\u{fffc}' // Object-replacement character
-        || c == char::REPLACEMENT_CHARACTER
-}
-
-/// Test whether `c` is a suspicious shell metacharacter which is unlikely to
-/// be worth assuming participates in a filename.
-fn is_suspicious_shell_metacharacter(c: char) -> bool {
-    // On Windows, backslash is a path separator.
-    #[cfg(windows)]
-    {
-        if c == '\\' {
-            return false;
+    /// Open a file for reading given an internal filename, restricted to the
+    /// byte range that it was granted with, if any.
+    ///
+    /// The returned file's offsets are relative to the start of the file,
+    /// and it starts out positioned at the start of the range. Reads that
+    /// start outside of the range fail with `PermissionDenied`, and reads
+    /// stop at the end of the range.
+    pub fn open_range(&self, path: &str) -> io::Result<RangedFile> {
+        let (grant, full_path) = self.find_grant(path, Access::Read)?;
+        if grant.stdin {
+            return Err(stdin_has_no_path(grant));
         }
+        let mut options = OpenOptions::new();
+        options.read(true);
+        let Some(range) = grant.range.clone() else {
+            // Open unranged files as `open` does, so that paths within a
+            // granted directory can't escape it.
+            let file = self.open_file(path, Access::Read, &options)?;
+            let len = file.metadata()?.len();
+            return RangedFile::new(file, 0..len);
+        };
+        // Ranged grants are always whole files, so there's no path within
+        // them to escape through.
+        let file = self.open_host_file(path, &full_path, &options)?;
+        RangedFile::new(file, range)
     }
 
-    matches!(
-        c,
-        '&' | '<' | '>' | '\\' | '|' | '?' | '*' | '[' | ']' | '"' | '\'' | ';'
-    )
-}
+    /// Open a file given an internal filename, and seek to `offset` bytes
+    /// from the start.
+    pub fn open_at_offset(&self, path: &str, offset: u64) -> io::Result<File> {
+        let mut file = self.open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        Ok(file)
+    }
 
-/// Apply some simple heuristics to determine whether `arg` is likely to refer
-/// to a filesystem path.
-///
-/// The heuristic roughly works like this:
-///
-///  - If it starts with a `-`, assume it's not a path.
-///  - If it contains a `/`, assume it is a path.
-///  - If it ends with a conventional-looking filename extension, or it looks
-///    like a dotile, assume it is a path.
-///  - Otherwise, assume it isn't.
-///
-/// There are also a few additional heuristics for rare situations.
-fn is_likely_path(arg: &str) -> bool {
-    // Exceptionally long strings are never filesystem paths.
-    if arg.len() > 4096 {
-        return false;
+    /// Create a file given an internal filename.
+    pub fn create(&self, path: &str) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        self.apply_create_mode(&mut options);
+        self.open_file(path, Access::Write, &options)
     }
 
-    if let Some(c) = arg.chars().next() {
-        // If the name starts with '-', assume it's meant to be a flag.
-        if c == '-' {
-            return false;
-        }
+    /// Open a file for appending given an internal filename.
+    pub fn append(&self, path: &str) -> io::Result<File> {
+        self.open_file(path, Access::Append, OpenOptions::new().append(true))
+    }
 
-        // On Windows, also assume a leading slash is meant to be a flag.
-        #[cfg(windows)]
-        if c == '/' {
-            return false;
-        }
+    /// Open a file for appending given an internal filename, creating it if
+    /// it doesn't exist, as is useful for log files.
+    pub fn append_create(&self, path: &str) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.append(true).create(true);
+        self.apply_create_mode(&mut options);
+        self.open_file(path, Access::Append, &options)
+    }
 
-        // If the name has leading whitespace, assume it's not a path.
-        if c.is_whitespace() {
-            return false;
-        }
+    /// Open a file for appending and reading given an internal filename,
+    /// creating it if it doesn't exist, like C's `a+` mode, so that a
+    /// program can read back what it appended.
+    ///
+    /// This requires `Append` access, as granted by `%append:`. Since the
+    /// file is also opened for reading, this lets the program read what was
+    /// in the file before it appended, as well as what it appended.
+    pub fn open_append_read(&self, path: &str) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.append(true).read(true).create(true);
+        self.apply_create_mode(&mut options);
+        self.open_file(path, Access::Append, &options)
+    }
 
-        // If the name starts with suspicious shell beginning-of-string
-        // metacharacters, don't give it the benefit of the doubt.
-        if matches!(c, '~' | '!') {
-            return false;
-        }
+    /// Open a file for reading and writing given an internal filename,
+    /// creating it if it doesn't exist, and without truncating it.
+    ///
+    /// This is the mode used by embedded databases, and requires
+    /// `ReadWrite` access, as granted by `%rwc:`.
+    pub fn open_read_write_create(&self, path: &str) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(true);
+        self.apply_create_mode(&mut options);
+        self.open_file(path, Access::ReadWrite, &options)
+    }
 
-        // We use a leading `%` as our escape character.
-        if c == '%' {
-            return false;
+    /// Apply the mode set by [`Pathbox::set_create_mode`], if any, to
+    /// `options`.
+    fn apply_create_mode(&self, options: &mut OpenOptions) {
+        #[cfg(unix)]
+        if let Some(mode) = self.create_mode {
+            std::os::unix::fs::OpenOptionsExt::mode(options, mode);
         }
 
-        // If the name starts with suspicious shell metacharacters, don't give
-        // it the benefit of the doubt.
-        if is_suspicious_shell_metacharacter(c) {
-            return false;
-        }
-    } else {
-        // Empty strings are never filesystem paths.
-        return false;
+        #[cfg(not(unix))]
+        let _ = (options, self.create_mode);
     }
 
-    // If any path-looking component begins or ends with whitespace, or ends
-    // with a `.` (without being `.` or `..` themselves) then assume it's not
-    // a path.
-    for component in std::path::Path::new(arg).components() {
-        let component = component.as_os_str().to_str().unwrap();
+    /// Append `line` and a newline to a file given an internal filename,
+    /// translating any internal filenames in `line` back into their
+    /// external presentation, as [`Pathbox::stdout`] does.
+    ///
+    /// The line is written with a single write, so that lines appended
+    /// concurrently by other processes aren't interleaved with it.
+    pub fn append_line(&self, path: &str, line: &str) -> io::Result<()> {
+        let mut input = Vec::with_capacity(line.len() + 1);
+        input.extend_from_slice(line.as_bytes());
+        input.push(b'\n');
 
-        // `.` and `..` are common path components.
-        if component == "." || component == ".." {
-            continue;
-        }
+        let mut buf = Vec::new();
+        let mut out = Vec::new();
+        crate::writer::translate_lines(self, &mut buf, &mut out, &input);
 
-        if let Some(first) = component.chars().next() {
-            if first.is_whitespace() {
-                return false;
-            }
-        } else {
-            return false;
-        }
-        let last = component.chars().rev().next().unwrap();
-        if last.is_whitespace() {
-            return false;
-        }
-        if last == '.' {
-            return false;
-        }
+        self.append(path)?.write_all(&out)
     }
 
-    // Filenames containing control characters aren't impossible, but are very
-    // rare and more likely to indicate something amiss than something normal.
-    if arg.chars().any(char::is_control) {
-        return false;
+    /// Read the entire contents of a file given an internal filename, like
+    /// `std::fs::read`.
+    ///
+    /// This requires `Read` access, as [`Pathbox::open`] does.
+    pub fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        crate::fs::read(self, path)
     }
 
-    // Now that we've ruled out patterns that are very likely to indicate that
-    // something is not meant to be a path, check for patterns which indicate
-    // that is likely to indicate that it is meant to be a path.
+    /// Write `data` as the entire contents of a file given an internal
+    /// filename, like `std::fs::write`.
+    ///
+    /// This requires `Write` access, as [`Pathbox::create`] does.
+    pub fn write(&self, path: &str, data: &[u8]) -> io::Result<()> {
+        crate::fs::write(self, path, data)
+    }
 
-    // If it contains a `/`, treat it as a path.
-    if arg.contains('/') {
-        return true;
+    /// Truncate or extend a file to `size` bytes given an internal filename.
+    pub fn truncate(&self, path: &str, size: u64) -> io::Result<()> {
+        let file = self.open_file(path, Access::Write, OpenOptions::new().write(true))?;
+        file.set_len(size)
     }
 
-    // Recognize Windows' special filenames as paths.
-    #[cfg(windows)]
-    {
-        let (start, _ext) = split_extension(arg);
-        for special in [
-            "CON", "PRN", "AUX", "NUL", "COM0", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6",
-            "COM7", "COM8", "COM9", "LPT0", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7",
-            "LPT8", "LPT9",
-        ] {
-            if start.eq_ignore_ascii_case(special) {
-                true;
-            }
-        }
+    /// Open a directory given an internal filename.
+    ///
+    /// The returned `DirView` doesn't include the host path in its `Debug`
+    /// output or in errors from operations on it, so it can be used by the
+    /// program without revealing where the directory is.
+    pub fn open_dir(&self, path: &str) -> io::Result<DirView> {
+        let dir = self.open_dir_with(path, Access::ReadonlyDir)?;
+        Ok(DirView::from_dir(dir, ViewKind::Readonly))
     }
 
-    // On Windows, if it starts with a path prefix, treat it as a path.
-    #[cfg(windows)]
-    if matches!(
-        std::path::Path::new(arg).components().next(),
-        Some(std::path::Component::Prefix(_))
-    ) {
-        return true;
+    /// Open a mutable directory given an internal filename.
+    pub fn open_mutable_dir(&self, path: &str) -> io::Result<DirView> {
+        let dir = self.open_dir_with(path, Access::MutableDir)?;
+        Ok(DirView::from_dir(dir, ViewKind::Full))
     }
 
-    // On Windows, if it contains `\\`, treat it as a path.
-    #[cfg(windows)]
-    if arg.contains('\\') {
-        return true;
+    /// Open a mutable directory given an internal filename, as a `cap_std`
+    /// `Dir`.
+    ///
+    /// A `Dir` permits all directory operations, so this requires mutable
+    /// directory access. Use [`Pathbox::open_dir`] for readonly directories.
+    pub fn open_cap_dir(&self, path: &str) -> io::Result<Dir> {
+        self.open_dir_with(path, Access::MutableDir)
     }
 
-    // Recognize plain filenames if they have a conventional-looking
-    // filename extension.
-    if let Some(ext) = std::path::Path::new(arg).extension() {
-        if let Some(ext) = ext.to_str() {
-            if !ext.is_empty() && ext.len() <= 16 && ext.chars().all(|c| c.is_ascii_alphanumeric())
-            {
-                return true;
+    /// Grant access to the directory at host path `path`, and return its
+    /// internal filename, to pass to the program, along with an open handle
+    /// to it, for the host's own use.
+    ///
+    /// If `mutable` is true, the directory is granted with mutable
+    /// directory access, otherwise with readonly directory access. The
+    /// directory is opened before the grant is recorded, so if opening it
+    /// fails, no grant is added.
+    pub fn grant_dir(
+        &mut self,
+        path: impl AsRef<OsStr>,
+        mutable: bool,
+    ) -> io::Result<(String, DirView)> {
+        let (access, kind) = if mutable {
+            (Access::MutableDir, ViewKind::Full)
+        } else {
+            (Access::ReadonlyDir, ViewKind::Readonly)
+        };
+        let original = self.normalize(path.as_ref().to_owned());
+        let dir = Dir::open_ambient_dir(&original, ambient_authority())?;
+        let token = self
+            .add_grant(original, access, None)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok((token, DirView::from_dir(dir, kind)))
+    }
+
+    /// Create a new uniquely named temporary file inside the mutable
+    /// directory with internal filename `dir`, and return its internal
+    /// filename along with a handle open for reading and writing.
+    ///
+    /// The file is created within the granted directory rather than the
+    /// system temporary directory, so this doesn't require any additional
+    /// access. It isn't removed automatically.
+    pub fn open_temp(&self, dir: &str) -> io::Result<(String, File)> {
+        let dir_view = self.open_mutable_dir(dir)?;
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create_new(true);
+        self.apply_create_mode(&mut options);
+        loop {
+            let name = format!(".tmp-{}", uuid::Uuid::new_v4());
+            match dir_view.open_with(&name, &options) {
+                Ok(file) => {
+                    let token = format!("{}/{}", dir.trim_end_matches('/'), name);
+                    return Ok((token, file));
+                }
+                // A collision is vanishingly unlikely, but if it happens,
+                // try another name.
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
             }
         }
     }
 
-    // Similarly, recognize Unix-style dot files.
-    if let Some(suffix) = arg.strip_prefix('.') {
-        if suffix.chars().all(|c| {
-            c.is_ascii_graphic()
-                && !matches!(
-                    c,
-                    '&' | '<' | '>' | '\\' | '|' | '?' | '*' | '[' | ']' | '"' | '\'' | ';'
-                )
-        }) {
-            return true;
+    /// Add each directory grant to a Wasmtime `WasiCtxBuilder` as a preopen,
+    /// using the grant's internal filename as the guest path.
+    ///
+    /// Grants with `Any` access are included if they refer to a directory.
+    #[cfg(feature = "wasmtime")]
+    pub fn populate_wasi_ctx(
+        &self,
+        builder: &mut wasmtime_wasi::WasiCtxBuilder,
+    ) -> Result<(), Error> {
+        use wasmtime_wasi::{DirPerms, FilePerms};
+
+        for grant in &self.grants {
+            let (dir_perms, file_perms) = match grant.access {
+                Access::ReadonlyDir => (DirPerms::READ, FilePerms::READ),
+                Access::MutableDir => (DirPerms::all(), FilePerms::all()),
+                Access::Any if std::path::Path::new(&grant.original).is_dir() => {
+                    (DirPerms::all(), FilePerms::all())
+                }
+                _ => continue,
+            };
+            builder
+                .preopened_dir(&grant.original, &grant.guest, dir_perms, file_perms)
+                .map_err(|err| {
+                    Error(format!(
+                        "Failed to preopen directory {:?}: {}",
+                        grant.original, err
+                    ))
+                })?;
         }
+        Ok(())
     }
 
-    false
-}
-
-#[test]
-fn test_is_likely_path() {
-    assert!(is_likely_path("/"));
-    assert!(is_likely_path("//"));
-    assert!(is_likely_path("."));
-    assert!(is_likely_path(".."));
-    assert!(is_likely_path("/."));
-    assert!(is_likely_path("/.."));
-    assert!(is_likely_path("./"));
-    assert!(is_likely_path("../"));
-    assert!(is_likely_path("hello.mp3"));
-    assert!(is_likely_path("world.JPEG"));
-    assert!(is_likely_path("goodnight.d"));
-    assert!(is_likely_path("moon.delightful"));
-    assert!(is_likely_path(".gitignore"));
-    assert!(is_likely_path(".this-and_that"));
-    assert!(is_likely_path("/foo"));
-    assert!(is_likely_path("/foo/bar"));
-    assert!(is_likely_path("/foo.baz/bar.baz"));
-    assert!(is_likely_path("foo/bar"));
-    assert!(is_likely_path("foo.baz/bar.baz"));
-    assert!(is_likely_path("foo/"));
-    assert!(is_likely_path("foo/bar/"));
-    assert!(is_likely_path("foo/bar/."));
-    assert!(is_likely_path("fo o/b ar"));
-    assert!(is_likely_path("f oo/ba r"));
-    assert!(is_likely_path(&"A/".repeat(2048)));
-
-    assert!(!is_likely_path(""));
-    assert!(!is_likely_path(".this and that"));
-    assert!(!is_likely_path("/hello\nworld.txt"));
-    assert!(!is_likely_path("/hello\tworld.txt"));
-    assert!(!is_likely_path("/hello\0world.txt"));
-    assert!(!is_likely_path(".this and that"));
-    assert!(!is_likely_path("<special/time.txt"));
-    assert!(!is_likely_path("!/what.txt"));
-    assert!(!is_likely_path("*/*/foo.md"));
-    assert!(!is_likely_path("foo"));
-    assert!(!is_likely_path(" /foo"));
-    assert!(!is_likely_path("foo /bar"));
-    assert!(!is_likely_path("foo/ bar"));
-    assert!(!is_likely_path("foo/bar."));
-    assert!(!is_likely_path("foo./bar"));
-    assert!(!is_likely_path("moon.excessivelylongextension"));
-    assert!(!is_likely_path(&"A/".repeat(2049)));
-
-    assert_eq!(is_likely_path("foo\\bar"), cfg!(windows));
-    assert_eq!(is_likely_path("\\foo\\bar"), cfg!(windows));
-    assert_eq!(is_likely_path("/A"), !cfg!(windows));
-    assert_eq!(is_likely_path("CON"), cfg!(windows));
-    assert_eq!(is_likely_path("NUL"), cfg!(windows));
-    assert_eq!(is_likely_path(r"\\?\pictures\kittens"), cfg!(windows));
-    assert_eq!(is_likely_path(r"\\?\UNC\server\share"), cfg!(windows));
-    assert_eq!(is_likely_path(r"\\?\c:\"), cfg!(windows));
-    assert_eq!(is_likely_path(r"\\.\BrainInterface"), cfg!(windows));
-    assert_eq!(is_likely_path(r"\\server\share"), cfg!(windows));
-    assert_eq!(
-        is_likely_path(r"C:\Users\Rust\Pictures\Ferris"),
-        cfg!(windows)
-    );
-}
+    /// Return a standard-output stream which translates any internal filenames
+    /// written to it into external filenames.
+    pub fn stdout(&self) -> Writer<'_> {
+        crate::writer::stdout(self, self.stdout.as_ref().map(|make| make()))
+    }
 
-#[test]
-fn test_split_extension() {
-    assert_eq!(split_extension("/foo/bar"), ("/foo/bar", ""));
-    assert_eq!(split_extension("/foo/bar.txt"), ("/foo/bar", ".txt"));
-    assert_eq!(
-        split_extension("/foo.qux/bar.txt"),
-        ("/foo.qux/bar", ".txt")
-    );
-    assert_eq!(split_extension("/foo/.bar"), ("/foo/.bar", ""));
-    assert_eq!(split_extension("/foo/.bar.txt"), ("/foo/.bar", ".txt"));
-    assert_eq!(
-        split_extension("/foo.qux/.bar.txt"),
-        ("/foo.qux/.bar", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo/.bar.txt.gz"),
-        ("/foo/.bar", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/.bar.txt.gz"),
-        ("/foo.qux/.bar", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.txt"),
-        ("/foo.qux/bar", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.txt.gz"),
-        ("/foo.qux/bar", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar..txt.gz"),
-        ("/foo.qux/bar.", ".txt.gz")
-    );
-    assert_eq!(split_extension("/foo.qux/bar.*"), ("/foo.qux/bar.*", ""));
-    assert_eq!(
-        split_extension("/foo.qux/bar.*.txt"),
-        ("/foo.qux/bar.*", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.*.txt.gz"),
-        ("/foo.qux/bar.*", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffd}"),
-        ("/foo.qux/bar.\u{fffd}", "")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffd}.txt"),
-        ("/foo.qux/bar.\u{fffd}", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffd}.txt.gz"),
-        ("/foo.qux/bar.\u{fffd}", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{feff}"),
-        ("/foo.qux/bar.\u{feff}", "")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{feff}.txt"),
-        ("/foo.qux/bar.\u{feff}", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{feff}.txt.gz"),
-        ("/foo.qux/bar.\u{feff}", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffa}"),
-        ("/foo.qux/bar.\u{fffa}", "")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffa}.txt"),
-        ("/foo.qux/bar.\u{fffa}", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffa}.txt.gz"),
-        ("/foo.qux/bar.\u{fffa}", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffb}"),
-        ("/foo.qux/bar.\u{fffb}", "")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffb}.txt"),
-        ("/foo.qux/bar.\u{fffb}", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffb}.txt.gz"),
-        ("/foo.qux/bar.\u{fffb}", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffc}"),
-        ("/foo.qux/bar.\u{fffc}", "")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffc}.txt"),
-        ("/foo.qux/bar.\u{fffc}", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{fffc}.txt.gz"),
-        ("/foo.qux/bar.\u{fffc}", ".txt.gz")
-    );
-    assert_eq!(split_extension("/foo.qux/bar. "), ("/foo.qux/bar. ", ""));
-    assert_eq!(
-        split_extension("/foo.qux/bar. .txt"),
-        ("/foo.qux/bar. ", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar. .txt.gz"),
-        ("/foo.qux/bar. ", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{7}"),
-        ("/foo.qux/bar.\u{7}", "")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{7}.txt"),
-        ("/foo.qux/bar.\u{7}", ".txt")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{7}.txt.gz"),
-        ("/foo.qux/bar.\u{7}", ".txt.gz")
-    );
-    assert_eq!(
-        split_extension("/foo.qux/bar.\u{7}. .*..txt.gz"),
-        ("/foo.qux/bar.\u{7}. .*.", ".txt.gz")
-    );
-    assert_eq!(split_extension("."), (".", ""));
-    assert_eq!(split_extension(".txt"), (".txt", ""));
-    assert_eq!(split_extension("a.txt"), ("a", ".txt"));
-    assert_eq!(split_extension("a..txt"), ("a.", ".txt"));
-}
+    /// Return a standard-error stream which translates any internal filenames
+    /// written to it into external filenames.
+    pub fn stderr(&self) -> Writer<'_> {
+        crate::writer::stderr(self, self.stderr.as_ref().map(|make| make()))
+    }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Flush the underlying standard output and standard error streams, or
+    /// the streams set with [`Pathbox::set_stdout`] and
+    /// [`Pathbox::set_stderr`].
+    ///
+    /// This doesn't reach [`Writer`]s which are still alive, since each has
+    /// its own buffer. A `Writer` writes all of its output, including any
+    /// partial line, to the underlying stream when it's flushed or dropped,
+    /// so flush or drop any `Writer`s first, and then call this before
+    /// exiting, or at any point where all output so far needs to have been
+    /// written.
+    pub fn flush_streams(&self) -> io::Result<()> {
+        let stdout = self.stdout().flush();
+        let stderr = self.stderr().flush();
+        stdout.and(stderr)
+    }
 
-    #[derive(Eq, PartialEq, Debug)]
-    struct Process {
-        arg: String,
-        grants: Vec<Grant>,
+    /// Return a stream which translates any internal filenames written to it
+    /// into external filenames, and writes the result to `inner`.
+    #[cfg(feature = "async")]
+    pub fn async_writer<W: tokio::io::AsyncWrite + Unpin>(
+        &self,
+        inner: W,
+    ) -> crate::AsyncWriter<'_, W> {
+        crate::AsyncWriter::new(self, inner)
     }
 
-    impl Process {
-        fn new(arg: &str, grants: &[Grant]) -> Self {
-            Self {
-                arg: arg.to_owned(),
-                grants: grants.to_vec(),
-            }
+    /// Print a log message which translatesa any internal filenames written
+    /// to it into external filenames.
+    ///
+    /// Messages below the level set by [`Pathbox::set_log_level`] are
+    /// ignored.
+    pub fn log(&self, level: Level, context: &str, message: &str) {
+        if self.log_level == Level::Off || level < self.log_level {
+            return;
         }
+        let color = self.log_color_override.unwrap_or(self.log_color);
+        log::log_with_color(&mut self.stderr(), level, context, message, color)
     }
 
-    fn do_process(arg: &str) -> Result<Process, Error> {
-        let mut pathbox = Pathbox::new(MagicLevel::Auto);
-        let arg = pathbox.process_args([arg.to_owned()].into_iter())?;
-        Ok(Process {
-            arg: arg[0].clone(),
-            grants: pathbox.as_slice().to_vec(),
-        })
+    /// Log one message at `level` for each grant, describing the access it
+    /// permits, so that operators can see what access a program received.
+    ///
+    /// Messages are written through [`Pathbox::log`], so the paths appear in
+    /// their external presentation.
+    pub fn log_grants(&self, level: Level) {
+        for grant in &self.grants {
+            let message = match &grant.range {
+                Some(range) => format!(
+                    "granted {} access to bytes {}..{} of {}",
+                    grant.access.name(),
+                    range.start,
+                    range.end,
+                    grant.guest
+                ),
+                None => format!("granted {} access to {}", grant.access.name(), grant.guest),
+            };
+            self.log(level, "pathbox", &message);
+        }
     }
 
-    fn do_process_os(arg: &OsStr) -> Result<Process, Error> {
-        let mut pathbox = Pathbox::new(MagicLevel::Auto);
-        let arg = pathbox.process_args_os([arg.to_owned()].into_iter())?;
-        Ok(Process {
-            arg: arg[0].clone(),
-            grants: pathbox.as_slice().to_vec(),
-        })
+    /// Build a `Command` which runs `program` with the given translated
+    /// arguments and environment variables.
+    ///
+    /// `vars` replaces the entire environment of the child process.
+    ///
+    /// On Unix, each grant, except those with `Any` access, is opened with its
+    /// granted access, and the file descriptor is inherited by the child
+    /// process.
+    /// Files granted for writing are opened without creating or truncating
+    /// them, so files which don't exist yet aren't passed. The
+    /// `PATHBOX_FDS` environment variable is set to a `:`-separated list of
+    /// `guest=fd` pairs, so that the child can find them.
+    ///
+    /// On other platforms, no file descriptors are passed.
+    pub fn command(
+        &self,
+        program: impl AsRef<OsStr>,
+        args: &[String],
+        vars: &[(String, String)],
+    ) -> io::Result<Command> {
+        crate::spawn::command(self, program.as_ref(), args, vars)
     }
 
-    #[test]
-    fn test_error() {
-        let args = ["%", "%%", "%%%", "%/foo"];
-        for arg in args {
-            assert!(do_process(arg).is_err());
-        }
+    /// Run `program` as a child process with the given translated arguments
+    /// and environment variables.
+    ///
+    /// See [`Pathbox::command`] for details.
+    pub fn spawn(
+        &self,
+        program: impl AsRef<OsStr>,
+        args: &[String],
+        vars: &[(String, String)],
+    ) -> io::Result<Child> {
+        self.command(program, args, vars)?.spawn()
     }
 
-    #[cfg(unix)]
-    #[test]
-    fn test_invalid() {
+    /// Replace any paths in `arg` with random UUIDs, and populate `self`
+    /// with information about the replacements.
+    fn process_os(&mut self, arg: OsString) -> Result<String, Error> {
+        match arg.into_string() {
+            // If it's valid Unicode, apply the normal processing rules.
+            Ok(s) => self.process(s),
+
+            // Interpret any ill-formed string as a filename path, because
+            // why else would there be an ill-formed command-line or
+            // environment variable string?
+            #[cfg(unix)]
+            Err(s) => {
+                let pending = self.pending_path_access.take();
+                let default_access = match pending.or(self.inferred_access()) {
+                    Some(access) => access,
+                    None => {
+                        return Err(Error(
+                            "ill-formed strings require a greater magic level".to_owned(),
+                        ))
+                    }
+                };
+                self.check_absolute(&s)?;
+                self.replace_os_with_uuid(&s, default_access)
+            }
+
+            #[cfg(not(unix))]
+            Err(_) => Err(Error("ill-formed strings are not permitted".to_owned())),
+        }
+    }
+
+    /// Replace any paths in `arg` with random UUIDs, and populate `self`
+    /// with information about the replacements.
+    fn process(&mut self, arg: String) -> Result<String, Error> {
+        self.process_annotated(arg).map(|(arg, _access)| arg)
+    }
+
+    /// Like [`Pathbox::process`], but also return the access that the
+    /// argument was translated with, or `None` if it was passed through.
+    fn process_annotated(&mut self, arg: String) -> Result<(String, Option<Access>), Error> {
+        // If the previous argument was a flag like `-o`, this argument is a
+        // path, unless it's escaped.
+        let pending = self.pending_path_access.take();
+
+        // Leading '%' is an escape to allow for special features.
+        if self.magic_level >= MagicLevel::Escapes {
+            if let Some(rest) = arg.strip_prefix('%') {
+                // `%verbatim:` means the remainder is a verbatim string.
+                if let Some(verbatim) = rest.strip_prefix("verbatim:") {
+                    return Ok((verbatim.to_owned(), None));
+                }
+                // `%read:-` means standard input.
+                if rest == "read:-" {
+                    return Ok((self.replace_stdin(), Some(Access::Read)));
+                }
+                // `%url-path:` means the remainder is a percent-encoded path
+                // to a file that may be opened for reading.
+                if let Some(encoded) = rest.strip_prefix("url-path:") {
+                    let path = decode_original(encoded)
+                        .ok_or_else(|| Error(format!("Invalid percent-encoding in {:?}", arg)))?;
+                    if path.to_string_lossy().contains('\0') {
+                        return Err(Error(format!("Decoded path contains NUL: {:?}", arg)));
+                    }
+                    return Ok((
+                        self.replace_os_with_uuid(&path, Access::Read)?,
+                        Some(Access::Read),
+                    ));
+                }
+                // `%read-exec:` means the remainder is a script that may be
+                // opened for reading and executed.
+                if let Some(path) = rest.strip_prefix("read-exec:") {
+                    return Ok((
+                        self.replace_with_uuid(path, Access::ReadExec)?,
+                        Some(Access::ReadExec),
+                    ));
+                }
+                // `%read:` means the remainder is a file that may be opened for reading.
+                if let Some(path) = rest.strip_prefix("read:") {
+                    return Ok((
+                        self.replace_with_uuid(path, Access::Read)?,
+                        Some(Access::Read),
+                    ));
+                }
+                // `%read-range:start-end:` means the remainder is a file that may be
+                // opened for reading bytes in the range `start..end`.
+                if let Some(rest) = rest.strip_prefix("read-range:") {
+                    let (range, path) = parse_range(rest)?;
+                    let guest = self.replace_range_with_uuid(path, Access::Read, Some(range))?;
+                    return Ok((guest, Some(Access::Read)));
+                }
+                // `%write:` means the remainder is a file that may be opened for writing,
+                // creating, and truncating.
+                if let Some(path) = rest.strip_prefix("write:") {
+                    self.check_escape(&arg, Access::Write)?;
+                    return Ok((
+                        self.replace_with_uuid(path, Access::Write)?,
+                        Some(Access::Write),
+                    ));
+                }
+                // `%append:` means the remainder is a file that may be opened for appending.
+                if let Some(path) = rest.strip_prefix("append:") {
+                    self.check_escape(&arg, Access::Append)?;
+                    return Ok((
+                        self.replace_with_uuid(path, Access::Append)?,
+                        Some(Access::Append),
+                    ));
+                }
+                // `%rwc:` means the remainder is a file that may be opened for
+                // reading and writing, and created.
+                if let Some(path) = rest.strip_prefix("rwc:") {
+                    self.check_escape(&arg, Access::ReadWrite)?;
+                    return Ok((
+                        self.replace_with_uuid(path, Access::ReadWrite)?,
+                        Some(Access::ReadWrite),
+                    ));
+                }
+                // `%none:` means the remainder is a path which is translated,
+                // but which may not be opened at all.
+                if let Some(path) = rest.strip_prefix("none:") {
+                    return Ok((
+                        self.replace_with_uuid(path, Access::None)?,
+                        Some(Access::None),
+                    ));
+                }
+                // `%dir:` means the remainder is a read-only directory.
+                if let Some(path) = rest.strip_prefix("dir:") {
+                    return Ok((
+                        self.replace_with_uuid(path, Access::ReadonlyDir)?,
+                        Some(Access::ReadonlyDir),
+                    ));
+                }
+                // `%mutable-dir:` means the remainder is a mutable directory.
+                if let Some(path) = rest.strip_prefix("mutable-dir:") {
+                    self.check_escape(&arg, Access::MutableDir)?;
+                    return Ok((
+                        self.replace_with_uuid(path, Access::MutableDir)?,
+                        Some(Access::MutableDir),
+                    ));
+                }
+                // `%temp-dir:` means a new temporary directory, created in the
+                // system's temporary directory, as a mutable directory.
+                if rest == "temp-dir:" {
+                    self.check_escape(&arg, Access::MutableDir)?;
+                    let temp_dir = tempfile::tempdir().map_err(|err| {
+                        Error(format!(
+                            "Failed to create a temporary directory for {:?}: {}",
+                            arg, err
+                        ))
+                    })?;
+                    let guest =
+                        self.replace_os_with_uuid(temp_dir.path().as_os_str(), Access::MutableDir)?;
+                    self.temp_dirs.push(temp_dir);
+                    return Ok((guest, Some(Access::MutableDir)));
+                }
+                // `%cwd` and `%mutable-cwd` mean the current working directory,
+                // as a read-only or mutable directory, respectively.
+                if rest == "cwd" || rest == "mutable-cwd" {
+                    let access = if rest == "cwd" {
+                        Access::ReadonlyDir
+                    } else {
+                        Access::MutableDir
+                    };
+                    self.check_escape(&arg, access)?;
+                    let cwd = std::env::current_dir().map_err(|err| {
+                        Error(format!(
+                            "Failed to determine the current directory for {:?}: {}",
+                            arg, err
+                        ))
+                    })?;
+                    return Ok((
+                        self.replace_os_with_uuid(cwd.as_os_str(), access)?,
+                        Some(access),
+                    ));
+                }
+
+                // Escapes defined by the host come after the built-in ones.
+                if let Some(handler) = &self.escape_handler {
+                    match handler(rest)? {
+                        EscapeResult::Verbatim(verbatim) => return Ok((verbatim, None)),
+                        EscapeResult::Grant(path, access) => {
+                            self.check_escape(&arg, access)?;
+                            return Ok((self.replace_os_with_uuid(&path, access)?, Some(access)));
+                        }
+                        EscapeResult::Unhandled => {}
+                    }
+                }
+
+                return Err(Error("Arguments beginning with '%' have special meanings. Prepend \"%verbatim:\" to pass a verbatim argument through.".to_owned()));
+            }
+
+            if let Some(default_access) = self.inferred_access() {
+                // Don't examine exceptionally long arguments.
+                if arg.len() > self.max_arg_bytes {
+                    return self.too_long(arg);
+                }
+
+                // If enabled, expand environment variables first, but pass
+                // the argument through unexpanded if it isn't a path. The
+                // expansion may be longer, so check its length too.
+                if self.expand_env_vars && arg.contains('$') {
+                    let expanded = expand_env_vars(&arg)?;
+                    if expanded.len() > self.max_arg_bytes {
+                        return self.too_long(arg);
+                    }
+                    let pending_path_access = self.pending_path_access;
+                    return match self.all_or_nothing(|this| {
+                        this.process_inferred(expanded, default_access, pending)
+                    })? {
+                        // The expansion isn't used, so it shouldn't affect
+                        // later arguments either, as it would if it were a
+                        // flag like `-o`.
+                        (_, None) => {
+                            self.pending_path_access = pending_path_access;
+                            Ok((arg, None))
+                        }
+                        translated => Ok(translated),
+                    };
+                }
+                return self
+                    .all_or_nothing(|this| this.process_inferred(arg, default_access, pending));
+            }
+        }
+
+        Ok((arg.to_owned(), None))
+    }
+
+    /// Handle an argument which is too long to examine for paths, by passing
+    /// it through verbatim, or in strict mode, reporting an error.
+    fn too_long(&self, arg: String) -> Result<(String, Option<Access>), Error> {
+        if self.strict {
+            return Err(Error(format!(
+                "Argument is too long to examine for paths; the limit is {} bytes",
+                self.max_arg_bytes
+            )));
+        }
+        Ok((arg, None))
+    }
+
+    /// Call `f`, and if it fails, undo any grants it made or widened, so
+    /// that an argument containing several paths, such as a colon-separated
+    /// list, grants either all of them or none.
+    fn all_or_nothing<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let len = self.grants.len();
+        let path_bytes = self.path_bytes;
+        let accesses: Vec<Access> = self.grants.iter().map(|grant| grant.access).collect();
+        let result = f(self);
+        if result.is_err() {
+            self.grants.truncate(len);
+            self.path_bytes = path_bytes;
+            for (grant, access) in self.grants.iter_mut().zip(accesses) {
+                grant.access = access;
+            }
+        }
+        result
+    }
+
+    /// Translate an argument which isn't an escape, inferring whether it's a
+    /// path, and granting `default_access` if so. `pending` is the access
+    /// for an argument following a flag like `-o`.
+    fn process_inferred(
+        &mut self,
+        arg: String,
+        default_access: Access,
+        pending: Option<Access>,
+    ) -> Result<(String, Option<Access>), Error> {
+        // By convention, `-` means standard input.
+        if arg == "-" {
+            return Ok((self.replace_stdin(), Some(Access::Read)));
+        }
+
+        // URIs with schemes that never contain paths are passed through.
+        if has_pathless_scheme(&arg) {
+            return Ok((arg, None));
+        }
+
+        if let Some(access) = pending {
+            return Ok((self.replace_inferred(&arg, access)?, Some(access)));
+        }
+
+        // Configured flags like `-o` are followed by a path.
+        if let Some((_flag, access)) = self.path_flags.iter().find(|(flag, _access)| *flag == arg) {
+            self.pending_path_access = Some(if default_access.includes(*access) {
+                *access
+            } else {
+                default_access
+            });
+            return Ok((arg, None));
+        }
+
+        // Configured flags like `-I` may have a path glued on.
+        if let Some(flag) = self
+            .glued_path_flags
+            .iter()
+            .find(|flag| arg.len() > flag.len() && arg.starts_with(**flag))
+        {
+            let path = self.replace_inferred(&arg[flag.len()..], default_access)?;
+            return Ok(((*flag).to_owned() + &path, Some(default_access)));
+        }
+
+        // On Windows, `C:foo`, `C:\foo`, and `C:` are single paths,
+        // not colon-separated lists.
+        #[cfg(windows)]
+        if self.heuristics.windows_paths()
+            && is_drive_path(&arg)
+            && self.heuristics.is_likely_path(&arg)
+        {
+            return Ok((
+                self.replace_inferred(&arg, default_access)?,
+                Some(default_access),
+            ));
+        }
+
+        // If enabled, translate paths within quoted strings.
+        if self.translate_quoted_words {
+            if let Some(arg) = self.replace_quoted_words(&arg, default_access)? {
+                return Ok((arg, Some(default_access)));
+            }
+        }
+
+        if arg.contains(':') {
+            // If enabled, prefer interpreting the argument as a single
+            // path if it names something that exists.
+            if self.colon_paths_exist && std::path::Path::new(&arg).exists() {
+                return Ok((
+                    self.replace_inferred(&arg, default_access)?,
+                    Some(default_access),
+                ));
+            }
+
+            // If all the parts between ':'s look like paths, interpret the
+            // argument as a colon-separated list of paths.
+            if arg
+                .split(':')
+                .all(|part| self.heuristics.is_likely_path(part))
+            {
+                let arg = arg
+                    .split(':')
+                    .map(|part| self.replace_inferred(part, default_access))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(":");
+                return Ok((arg, Some(default_access)));
+            }
+
+            // Otherwise, if enabled, translate just the parts which
+            // look like paths.
+            if self.partial_colon_lists
+                && arg
+                    .split(':')
+                    .any(|part| self.heuristics.is_likely_path(part))
+            {
+                let arg = arg
+                    .split(':')
+                    .map(|part| {
+                        if self.heuristics.is_likely_path(part) {
+                            self.replace_inferred(part, default_access)
+                        } else {
+                            Ok(part.to_owned())
+                        }
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(":");
+                return Ok((arg, Some(default_access)));
+            }
+
+            return Ok((arg.to_owned(), None));
+        }
+
+        if let Some(eq) = arg.find('=') {
+            let (prefix, suffix) = arg.split_at(eq + 1);
+            if !prefix.contains('/') {
+                if self.heuristics.is_likely_path(suffix) {
+                    // No slash before the '=' and a slash after; treat it as
+                    // a `--input=/path/to/file.txt` case and replace the path part.
+                    let path = self.replace_inferred(suffix, default_access)?;
+                    return Ok((prefix.to_owned() + &path, Some(default_access)));
+                }
+                self.check_ambiguous(suffix)?;
+            } else {
+                // Paths on both sides of the '='; treat it as a
+                // `/src/map=/dst/map` mapping and replace both. Paths like
+                // `/data/year=2024/part.parquet` contain '='s too, so only do
+                // this if both sides are absolute, or if the whole argument
+                // doesn't look like a path.
+                let key = &prefix[..eq];
+                let mapping = (key.starts_with(is_separator) && suffix.starts_with(is_separator))
+                    || !self.heuristics.is_likely_path(&arg);
+                if mapping
+                    && self.heuristics.is_likely_path(key)
+                    && self.heuristics.is_likely_path(suffix)
+                {
+                    let key = self.replace_inferred(key, default_access)?;
+                    let path = self.replace_inferred(suffix, default_access)?;
+                    return Ok((key + "=" + &path, Some(default_access)));
+                }
+            }
+        }
+
+        if self.heuristics.is_likely_path(&arg) {
+            return Ok((
+                self.replace_inferred(&arg, default_access)?,
+                Some(default_access),
+            ));
+        }
+        self.check_ambiguous(&arg)?;
+
+        Ok((arg, None))
+    }
+
+    /// Return the access to grant to inferred paths, or `None` if the magic
+    /// level doesn't infer paths.
+    fn inferred_access(&self) -> Option<Access> {
+        match self.magic_level {
+            MagicLevel::Auto => Some(Access::Any),
+            MagicLevel::Readonly => Some(Access::Read),
+            MagicLevel::Escapes | MagicLevel::None => None,
+        }
+    }
+
+    /// Translate the words that look like paths within quoted strings in
+    /// `arg`, or return `None` if there aren't any.
+    fn replace_quoted_words(&mut self, arg: &str, access: Access) -> Result<Option<String>, Error> {
+        let mut out = String::new();
+        let mut found = false;
+        let mut rest = arg;
+        while let Some(open) = rest.find(['"', '\'']) {
+            let quote = &rest[open..open + 1];
+            let Some(len) = rest[open + 1..].find(quote) else {
+                break;
+            };
+            let quoted = &rest[open + 1..open + 1 + len];
+            out.push_str(&rest[..open + 1]);
+            for piece in quoted.split_inclusive(char::is_whitespace) {
+                let word = piece.trim_end_matches(char::is_whitespace);
+                if self.heuristics.is_likely_path(word) {
+                    out.push_str(&self.replace_inferred(word, access)?);
+                    found = true;
+                } else {
+                    out.push_str(word);
+                }
+                out.push_str(&piece[word.len()..]);
+            }
+            out.push_str(quote);
+            rest = &rest[open + 1 + len + 1..];
+        }
+        out.push_str(rest);
+        Ok(found.then_some(out))
+    }
+
+    /// Translate an environment variable name, if `translate_var_names` is
+    /// enabled and it looks like a path.
+    fn process_var_name(&mut self, key: String) -> Result<String, Error> {
+        if self.translate_var_names && self.heuristics.is_likely_path(&key) {
+            if let Some(access) = self.inferred_access() {
+                return self.replace_inferred(&key, access);
+            }
+        }
+        Ok(key)
+    }
+
+    /// In strict mode, fail if `s`, which has been rejected by the path
+    /// heuristics, nonetheless looks like it was meant to be a path.
+    fn check_ambiguous(&self, s: &str) -> Result<(), Error> {
+        let has_separator = s.contains('/') || (cfg!(windows) && s.contains('\\'));
+        if self.strict && has_separator && !s.starts_with('-') {
+            return Err(Error(format!(
+                "{:?} looks like a path, but isn't recognized as one. Prepend \"%verbatim:\" to pass it through verbatim, or \"%read:\" or similar to grant access to it.",
+                s
+            )));
+        }
+        Ok(())
+    }
+
+    /// Like [`Pathbox::replace_with_uuid`], but for paths which were inferred
+    /// rather than explicitly escaped.
+    fn replace_inferred(&mut self, s: &str, access: Access) -> Result<String, Error> {
+        // Don't translate internal filenames which have already been
+        // translated.
+        if self
+            .grants
+            .iter()
+            .any(|grant| match_grant(grant, s).is_some())
+        {
+            return Ok(s.to_owned());
+        }
+        self.check_absolute(s.as_ref())?;
+        self.replace_with_uuid(s, access)
+    }
+
+    /// Report an error if escapes are limited by the magic level and `access`
+    /// exceeds the limit.
+    fn check_escape(&self, arg: &str, access: Access) -> Result<(), Error> {
+        if self.limit_escapes
+            && self.magic_level == MagicLevel::Readonly
+            && access.permits_modification()
+        {
+            return Err(Error(format!(
+                "{:?} requests {} access, but only read-only access is permitted",
+                arg,
+                access.name()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Report an error if absolute inferred paths are forbidden and `path`
+    /// is absolute.
+    fn check_absolute(&self, path: &OsStr) -> Result<(), Error> {
+        if self.forbid_absolute && Path::new(path).is_absolute() {
+            return Err(Error(format!(
+                "Absolute paths are forbidden; {:?} must be relative",
+                path
+            )));
+        }
+        Ok(())
+    }
+
+    fn replace_with_uuid(&mut self, s: &str, access: Access) -> Result<String, Error> {
+        self.replace_range_with_uuid(s, access, None)
+    }
+
+    fn replace_range_with_uuid(
+        &mut self,
+        s: &str,
+        access: Access,
+        range: Option<Range<u64>>,
+    ) -> Result<String, Error> {
+        self.add_grant(s.to_owned().into(), access, range)
+    }
+
+    fn replace_os_with_uuid(&mut self, s: &OsStr, access: Access) -> Result<String, Error> {
+        self.add_grant(s.to_owned(), access, None)
+    }
+
+    /// Generate a new guest path for `s`, which keeps its extension unless
+    /// extensions are hidden.
+    fn new_guest(&mut self, s: &str) -> String {
+        let (stem, ext) = split_extension(s);
+        let ext = if self.hide_extensions { "" } else { ext };
+        if !self.readable_tokens {
+            return format!("{}{}{}", GUEST_PREFIX, uuid::Uuid::new_v4(), ext);
+        }
+
+        // Put the counter first, followed by the separator, so that no token
+        // is a prefix of another, and skip any numbers already in use, such
+        // as by grants inherited with `from_env`. Names never contain `.`,
+        // so the extension is unambiguous.
+        let name: String = stem
+            .rsplit(is_separator)
+            .find(|component| !component.is_empty() && *component != "." && *component != "..")
+            .unwrap_or("path")
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .take(32)
+            .collect();
+        loop {
+            self.next_token += 1;
+            let prefix = format!(
+                "{}{}{}",
+                GUEST_PREFIX, self.next_token, self.readable_token_separator
+            );
+            if !self
+                .grants
+                .iter()
+                .any(|grant| grant.guest.starts_with(&prefix))
+            {
+                return format!("{}{}{}", prefix, name, ext);
+            }
+        }
+    }
+
+    /// Record a grant, and return the guest path to use for it.
+    ///
+    /// If `original` has already been granted with the same byte range, the
+    /// existing grant's access is widened to the least upper bound of the two
+    /// accesses, as described in [`Access::merge`], and its guest path is
+    /// returned instead. If the accesses can't be merged, it's an error.
+    /// Grants with different byte ranges are kept separate, so that
+    /// restricted grants are never widened. A new guest path is only
+    /// generated when a new grant is recorded, so that merging doesn't use
+    /// up numbers when tokens are readable.
+    fn add_grant(
+        &mut self,
+        original: OsString,
+        access: Access,
+        range: Option<Range<u64>>,
+    ) -> Result<String, Error> {
+        // The OS can't open paths containing NUL, so reject them here rather
+        // than failing with a confusing error when they're opened.
+        if original.to_string_lossy().contains('\0') {
+            return Err(Error(format!("Path contains NUL: {:?}", original)));
+        }
+        let original = self.normalize(original);
+        check_executable(&original, access, self.non_executable_extensions)?;
+        if let Some(grant) = self
+            .grants
+            .iter_mut()
+            .find(|g| same_original(&g.original, &original) && g.range == range && !g.stdin)
+        {
+            grant.access = grant
+                .access
+                .merge(access)
+                .ok_or_else(|| incompatible_access(grant, access))?;
+            return Ok(grant.guest.clone());
+        }
+
+        if self.grants.len() >= self.max_grants {
+            return Err(Error(format!(
+                "Too many paths; the limit is {}",
+                self.max_grants
+            )));
+        }
+        let path_bytes = self.path_bytes.saturating_add(original.len());
+        if path_bytes > self.max_path_bytes {
+            return Err(Error(format!(
+                "Paths are too long in total; the limit is {} bytes",
+                self.max_path_bytes
+            )));
+        }
+        self.path_bytes = path_bytes;
+
+        // Replacement characters are never part of an extension, so any
+        // extension found in the lossy string is from the original.
+        let guest = self.new_guest(&original.to_string_lossy());
+        self.grants.push(Grant {
+            guest: guest.clone(),
+            original,
+            access,
+            range,
+            stdin: false,
+        });
+        Ok(guest)
+    }
+
+    /// Normalize `original`, if enabled.
+    fn normalize(&self, original: OsString) -> OsString {
+        if !self.normalize_paths {
+            return original;
+        }
+
+        #[cfg(unix)]
+        {
+            OsString::from_vec(normalize_path(original.as_bytes(), self.normalize_dot_dot))
+        }
+
+        // Only ASCII bytes are removed, so the result is still valid UTF-8.
+        #[cfg(not(unix))]
+        match original.to_str() {
+            Some(s) => String::from_utf8(normalize_path(s.as_bytes(), self.normalize_dot_dot))
+                .unwrap()
+                .into(),
+            None => original,
+        }
+    }
+
+    /// Record a grant of standard input, and return the guest path to use
+    /// for it. There's only one standard input, so this always returns the
+    /// same guest path.
+    fn replace_stdin(&mut self) -> String {
+        if let Some(grant) = self.grants.iter().find(|g| g.stdin) {
+            return grant.guest.clone();
+        }
+
+        let guest = format!("{}{}", GUEST_PREFIX, uuid::Uuid::new_v4());
+        self.grants.push(Grant {
+            guest: guest.clone(),
+            original: "-".into(),
+            access: Access::Read,
+            range: None,
+            stdin: true,
+        });
+        guest
+    }
+
+    pub(crate) fn as_slice(&self) -> &[Grant] {
+        &self.grants
+    }
+
+    pub(crate) fn output_separator(&self) -> Option<char> {
+        self.output_separator
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+pub struct Error(pub(crate) String);
+
+impl Error {
+    /// Construct a new error with the given message, such as for returning
+    /// from a handler set with [`Pathbox::set_escape_handler`].
+    pub fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl std::fmt::Debug for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        self.0.fmt(f)
+    }
+}
+
+/// A record of a name which has been replaced.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Grant {
+    /// The replacement string to present to the guest.
+    pub guest: String,
+
+    /// The original string to use in the host.
+    pub original: OsString,
+
+    /// How the file may be accessed.
+    pub access: Access,
+
+    /// The range of bytes which may be accessed, or `None` if the whole file
+    /// may be accessed.
+    pub range: Option<Range<u64>>,
+
+    /// Whether this grant refers to standard input rather than to a file,
+    /// in which case `original` is `-`.
+    pub stdin: bool,
+}
+
+/// A read-only view of a grant, as returned by [`Pathbox::preopens`].
+#[derive(Clone, Copy, Debug)]
+pub struct PreopenInfo<'a> {
+    grant: &'a Grant,
+}
+
+impl<'a> PreopenInfo<'a> {
+    /// Return the internal filename presented to the program.
+    pub fn token(&self) -> &'a str {
+        &self.grant.guest
+    }
+
+    /// Return the host path, or `-` for standard input.
+    pub fn original(&self) -> &'a OsStr {
+        &self.grant.original
+    }
+
+    /// Return how the file may be accessed.
+    pub fn access(&self) -> Access {
+        self.grant.access
+    }
+}
+
+/// Call `f`, retrying for as long as it fails with `Interrupted`, which
+/// happens on Unix when a signal arrives during a system call.
+pub(crate) fn retry_on_interrupt<T>(mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    loop {
+        match f() {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Expand references to environment variables, written `$VAR` or `${VAR}`,
+/// in `arg`. A `$` which isn't followed by a variable name is kept as is.
+fn expand_env_vars(arg: &str) -> Result<String, Error> {
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let mut out = String::new();
+    let mut rest = arg;
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        let after = &rest[dollar + 1..];
+        let (name, next) = if let Some(braced) = after.strip_prefix('{') {
+            let close = braced
+                .find('}')
+                .ok_or_else(|| Error(format!("Unterminated \"${{\" in {:?}", arg)))?;
+            (&braced[..close], &braced[close + 1..])
+        } else {
+            let len = after.find(|c| !is_name_char(c)).unwrap_or(after.len());
+            (&after[..len], &after[len..])
+        };
+        if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+            out.push('$');
+            rest = after;
+            continue;
+        }
+        let value = std::env::var(name).map_err(|err| {
+            Error(format!(
+                "Failed to expand environment variable {:?} in {:?}: {}",
+                name, arg, err
+            ))
+        })?;
+        out.push_str(&value);
+        rest = next;
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// If `grant` matches the internal filename `path`, return the remainder of
+/// the filename after the grant's guest path.
+fn match_grant<'p>(grant: &Grant, path: &'p str) -> Option<&'p str> {
+    let rest = path.strip_prefix(&grant.guest)?;
+    (rest.is_empty() || rest.starts_with(is_separator)).then_some(rest)
+}
+
+/// Test whether `path` has the shape of an internal filename, or a path
+/// within one: the `guest-path.` prefix, followed by a name which doesn't
+/// start with a separator.
+fn has_guest_shape(path: &str) -> bool {
+    path.strip_prefix(GUEST_PREFIX)
+        .and_then(|name| name.chars().next())
+        .is_some_and(|c| !is_separator(c))
+}
+
+/// The error for a path which isn't granted at all, which is distinguished
+/// from being granted with the wrong access so that callers can tell them
+/// apart.
+fn not_available() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "File is not available")
+}
+
+/// The error for widening `grant` to include `access` when the accesses
+/// can't be merged.
+fn incompatible_access(grant: &Grant, access: Access) -> Error {
+    Error(format!(
+        "{:?} is granted {} access, which can't be combined with {} access",
+        grant.original,
+        grant.access.name(),
+        access.name()
+    ))
+}
+
+/// The error for a path which is granted, but not with the needed access.
+fn access_denied(grant: &Grant) -> io::Error {
+    let access = match grant.access {
+        Access::Read => "read",
+        Access::Write => "write",
+        Access::Append => "append",
+        Access::ReadWrite => "read/write",
+        Access::ReadExec => "read/execute",
+        Access::ReadonlyDir => "readonly directory",
+        Access::MutableDir => "read/write directory",
+        Access::None => "no",
+        Access::Any => "any",
+    };
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Grant '{:?}' only permits {:?} access", grant.guest, access),
+    )
+}
+
+/// Fold function which keeps whichever of two matches has the longer guest
+/// path, preferring the first if they're the same length.
+fn longest_match<'a, 'p>(
+    longest: Option<(&'a Grant, &'p str)>,
+    next: (&'a Grant, &'p str),
+) -> Option<(&'a Grant, &'p str)> {
+    match longest {
+        Some(longest) if longest.0.guest.len() >= next.0.guest.len() => Some(longest),
+        _ => Some(next),
+    }
+}
+
+/// If the message of `err` mentions the host path `host`, replace it with
+/// the internal filename `internal`, so that printing the error doesn't
+/// reveal the host path.
+///
+/// Errors from the OS don't typically include paths, so `err` is usually
+/// returned as is, preserving its OS error code.
+pub(crate) fn hide_host_path(err: io::Error, host: &Path, internal: &str) -> io::Error {
+    let host = host.to_string_lossy();
+    let message = err.to_string();
+    if host.is_empty() || !message.contains(&*host) {
+        return err;
+    }
+    io::Error::new(err.kind(), message.replace(&*host, internal))
+}
+
+/// Fail if `is_file`, the result of checking whether the file with internal
+/// filename `path` is a regular file, is `false`. If the check itself
+/// failed, opening the file will fail too, so defer to that.
+fn check_regular(path: &str, is_file: io::Result<bool>) -> io::Result<()> {
+    match is_file {
+        Ok(false) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{:?} is not a regular file", path),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Return a new handle to standard input.
+fn dup_stdin() -> io::Result<File> {
+    #[cfg(not(windows))]
+    let owned = std::os::fd::AsFd::as_fd(&io::stdin()).try_clone_to_owned()?;
+    #[cfg(windows)]
+    let owned = std::os::windows::io::AsHandle::as_handle(&io::stdin()).try_clone_to_owned()?;
+    Ok(File::from_std(std::fs::File::from(owned)))
+}
+
+/// The error for attempting to use standard input as a file with a path.
+fn stdin_has_no_path(grant: &Grant) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "Grant '{:?}' refers to standard input, which has no path",
+            grant.guest
+        ),
+    )
+}
+
+/// URI schemes which never refer to filesystem paths.
+const PATHLESS_SCHEMES: &[&str] = &[
+    "about",
+    "data",
+    "geo",
+    "javascript",
+    "magnet",
+    "mailto",
+    "news",
+    "sip",
+    "sips",
+    "sms",
+    "tel",
+    "urn",
+    "xmpp",
+];
+
+/// Test whether `arg` is a URI with one of the [`PATHLESS_SCHEMES`].
+fn has_pathless_scheme(arg: &str) -> bool {
+    match arg.split_once(':') {
+        Some((scheme, _rest)) => PATHLESS_SCHEMES
+            .iter()
+            .any(|pathless| scheme.eq_ignore_ascii_case(pathless)),
+        None => false,
+    }
+}
+
+/// Parse the `start-end:path` part of a `%read-range:` argument.
+fn parse_range(s: &str) -> Result<(Range<u64>, &str), Error> {
+    let malformed = || {
+        Error(format!(
+            "Malformed byte range in {:?}; expected \"%read-range:start-end:path\"",
+            s
+        ))
+    };
+    let (range, path) = s.split_once(':').ok_or_else(malformed)?;
+    let (start, end) = range.split_once('-').ok_or_else(malformed)?;
+    let start = start.parse::<u64>().map_err(|_| malformed())?;
+    let end = end.parse::<u64>().map_err(|_| malformed())?;
+    if end < start {
+        return Err(malformed());
+    }
+    Ok((start..end, path))
+}
+
+/// What types of file access should be permitted?
+///
+/// Accesses are partially ordered by inclusion, forming a lattice:
+///
+///  - `Append` < `Write` < `ReadWrite`
+///  - `Read` < `ReadWrite`
+///  - `Read` < `ReadExec`
+///  - `ReadonlyDir` < `MutableDir`
+///  - every other access < `Any`
+///  - `None` < every other access
+///
+/// Accesses not related by these rules, such as `Read` and `Write`, are
+/// incomparable.
+///
+/// Each [`Pathbox`] method which opens a file or directory requires an
+/// access, and fails unless the grant's access includes it:
+///
+/// | Method                                                            | Required access |
+/// |-------------------------------------------------------------------|-----------------|
+/// | `open`, `open_resolved`, `open_range`, `open_at_offset`, `reopen` | `Read`          |
+/// | `open_mmap`                                                       | `Read`          |
+/// | `open_executable`                                                 | `ReadExec`      |
+/// | `create`, `truncate`                                              | `Write`         |
+/// | `append`, `append_create`, `append_line`, `open_append_read`      | `Append`        |
+/// | `open_read_write_create`                                          | `ReadWrite`     |
+/// | `open_dir`                                                        | `ReadonlyDir`   |
+/// | `open_mutable_dir`, `open_cap_dir`, `open_temp`                   | `MutableDir`    |
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Access {
+    /// Allow read-only operations.
+    Read,
+    /// Allow writing, creating, and truncating files. This includes
+    /// appending.
+    Write,
+    /// Allow appending to files.
+    Append,
+    /// Allow reading and writing files.
+    ReadWrite,
+    /// Allow reading files and executing them, such as scripts run by an
+    /// interpreter.
+    ReadExec,
+    /// Allow read-only directory operations.
+    ReadonlyDir,
+    /// Allow all directory operations.
+    MutableDir,
+    /// Allow any access to files.
+    Any,
+    /// Allow no access. The path is translated, but may not be opened. This
+    /// is useful for paths which are only displayed, or which are accessed
+    /// by other means.
+    None,
+}
+
+impl Access {
+    /// Test whether `self` permits everything that `other` permits.
+    pub fn includes(&self, other: Self) -> bool {
+        *self == other
+            || matches!(
+                (*self, other),
+                (Access::Any, _)
+                    | (_, Access::None)
+                    | (Access::MutableDir, Access::ReadonlyDir)
+                    | (Access::Write, Access::Append)
+                    | (
+                        Access::ReadWrite,
+                        Access::Read | Access::Write | Access::Append
+                    )
+                    | (Access::ReadExec, Access::Read)
+            )
+    }
+
+    /// Return the least access which includes both `self` and `other`, and
+    /// nothing which neither of them permits, or `None` if there isn't one.
+    ///
+    /// For example, `Read` merged with `Write` is `ReadWrite`, and
+    /// `ReadonlyDir` merged with `MutableDir` is `MutableDir`. `Read` and
+    /// `Append` can't be merged, since `ReadWrite` would also permit
+    /// overwriting, and neither can a file access and a directory access.
+    pub fn merge(self, other: Self) -> Option<Self> {
+        if self.includes(other) {
+            Some(self)
+        } else if other.includes(self) {
+            Some(other)
+        } else if matches!(
+            (self, other),
+            (Access::Read, Access::Write) | (Access::Write, Access::Read)
+        ) {
+            Some(Access::ReadWrite)
+        } else {
+            None
+        }
+    }
+
+    /// Test whether this access permits modifying files or directories.
+    fn permits_modification(self) -> bool {
+        !matches!(
+            self,
+            Access::Read | Access::ReadExec | Access::ReadonlyDir | Access::None
+        )
+    }
+
+    /// The name of this access, as used in `%`-escapes.
+    fn name(self) -> &'static str {
+        match self {
+            Access::Read => "read",
+            Access::Write => "write",
+            Access::Append => "append",
+            Access::ReadWrite => "read-write",
+            Access::ReadExec => "read-exec",
+            Access::ReadonlyDir => "dir",
+            Access::MutableDir => "mutable-dir",
+            Access::Any => "any",
+            Access::None => "none",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "read" => Access::Read,
+            "write" => Access::Write,
+            "append" => Access::Append,
+            "read-write" => Access::ReadWrite,
+            "read-exec" => Access::ReadExec,
+            "dir" => Access::ReadonlyDir,
+            "mutable-dir" => Access::MutableDir,
+            "any" => Access::Any,
+            "none" => Access::None,
+            _ => return None,
+        })
+    }
+}
+
+impl PartialOrd for Access {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        if self == other {
+            Some(std::cmp::Ordering::Equal)
+        } else if self.includes(*other) {
+            Some(std::cmp::Ordering::Greater)
+        } else if other.includes(*self) {
+            Some(std::cmp::Ordering::Less)
+        } else {
+            None
+        }
+    }
+}
+
+/// Test whether two grants' originals refer to the same path, for the
+/// purpose of merging them.
+///
+/// On Windows, paths are compared case-insensitively, with `/` and `\` being
+/// equivalent, and ignoring a `\\?\` prefix on drive paths. Elsewhere,
+/// they're compared exactly.
+fn same_original(a: &OsStr, b: &OsStr) -> bool {
+    #[cfg(windows)]
+    if let (Some(a), Some(b)) = (a.to_str(), b.to_str()) {
+        return fold_windows_path(a) == fold_windows_path(b);
+    }
+
+    a == b
+}
+
+/// Fold a Windows path into a form where paths which differ only in case,
+/// separators, or a `\\?\` prefix on a drive path are equal.
+#[cfg(any(windows, test))]
+fn fold_windows_path(path: &str) -> String {
+    let is_disk =
+        |s: &str| matches!(s.as_bytes(), [drive, b':', ..] if drive.is_ascii_alphabetic());
+    let path = match path.strip_prefix(r"\\?\") {
+        Some(rest) if is_disk(rest) => rest,
+        _ => path,
+    };
+    path.replace('/', "\\").to_lowercase()
+}
+
+/// Check that `original` doesn't have one of `extensions`, if `access` is
+/// `ReadExec`.
+fn check_executable(original: &OsStr, access: Access, extensions: &[&str]) -> Result<(), Error> {
+    if access != Access::ReadExec {
+        return Ok(());
+    }
+    let ext = Path::new(original).extension().and_then(OsStr::to_str);
+    match ext {
+        Some(ext) if extensions.iter().any(|e| e.eq_ignore_ascii_case(ext)) => Err(Error(format!(
+            "{:?} has a non-executable extension, and can't be granted read/execute access",
+            original
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Remove redundant separators and `.` components from `path`, and if
+/// `dot_dot` is set, resolve `..` components lexically.
+fn normalize_path(path: &[u8], dot_dot: bool) -> Vec<u8> {
+    let is_sep = |b: &u8| *b == b'/' || (cfg!(windows) && *b == b'\\');
+
+    // Preserve a leading `//`, which may be significant, but not `///`.
+    let root_len = match path {
+        [a, b, c, ..] if is_sep(a) && is_sep(b) && !is_sep(c) => 2,
+        [a, b] if is_sep(a) && is_sep(b) => 2,
+        [a, ..] if is_sep(a) => 1,
+        _ => 0,
+    };
+    let (root, rest) = path.split_at(root_len);
+
+    let mut components: Vec<&[u8]> = Vec::new();
+    for component in rest.split(is_sep) {
+        match component {
+            b"" | b"." => {}
+            // Don't resolve `..` past a `..` or a Windows drive like `C:`.
+            b".." if dot_dot => match components.last() {
+                Some(last) if *last != b".." && !last.ends_with(b":") => {
+                    components.pop();
+                }
+                // `..` at the root is the root.
+                None if root_len != 0 => {}
+                _ => components.push(component),
+            },
+            _ => components.push(component),
+        }
+    }
+
+    let sep = if cfg!(windows) && path.contains(&b'\\') {
+        b'\\'
+    } else {
+        b'/'
+    };
+    let mut normalized = root.to_vec();
+    for (i, component) in components.iter().enumerate() {
+        if i != 0 {
+            normalized.push(sep);
+        }
+        normalized.extend_from_slice(component);
+    }
+    if components.is_empty() {
+        if root_len == 0 {
+            normalized.push(b'.');
+        }
+    } else if path.last().is_some_and(is_sep) {
+        // A trailing separator requires the path to be a directory.
+        normalized.push(sep);
+    }
+    normalized
+}
+
+/// Append `original` to `out`, percent-encoding any bytes which are `%`,
+/// whitespace, or not printable ASCII.
+fn encode_original(original: &OsStr, out: &mut String) {
+    #[cfg(unix)]
+    let bytes = original.as_bytes();
+    #[cfg(not(unix))]
+    let bytes = original.to_str().unwrap().as_bytes();
+
+    for &b in bytes {
+        if b.is_ascii_graphic() && b != b'%' {
+            out.push(char::from(b));
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+}
+
+/// Decode a string encoded by `encode_original`.
+fn decode_original(s: &str) -> Option<OsString> {
+    let mut bytes = Vec::new();
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+
+    #[cfg(unix)]
+    return Some(OsString::from_vec(bytes));
+    #[cfg(not(unix))]
+    return String::from_utf8(bytes).ok().map(OsString::from);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Eq, PartialEq, Debug)]
+    struct Process {
+        arg: String,
+        grants: Vec<Grant>,
+    }
+
+    impl Process {
+        fn new(arg: &str, grants: &[Grant]) -> Self {
+            Self {
+                arg: arg.to_owned(),
+                grants: grants.to_vec(),
+            }
+        }
+    }
+
+    fn do_process(arg: &str) -> Result<Process, Error> {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let arg = pathbox.process_args([arg.to_owned()].into_iter())?;
+        Ok(Process {
+            arg: arg[0].clone(),
+            grants: pathbox.as_slice().to_vec(),
+        })
+    }
+
+    fn do_process_os(arg: &OsStr) -> Result<Process, Error> {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let arg = pathbox.process_args_os([arg.to_owned()].into_iter())?;
+        Ok(Process {
+            arg: arg[0].clone(),
+            grants: pathbox.as_slice().to_vec(),
+        })
+    }
+
+    #[test]
+    fn test_error() {
+        let args = ["%", "%%", "%%%", "%/foo"];
+        for arg in args {
+            assert!(do_process(arg).is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_invalid() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let args = [
+            &b"hi/\xc0"[..],
+            &b"hello/\xff"[..],
+            &b"greetings/hello\x80world"[..],
+        ];
+        for arg in args {
+            let p = do_process_os(OsStr::from_bytes(arg)).unwrap();
+            assert_eq!(p.grants.len(), 1);
+            assert_eq!(p.arg, p.grants[0].guest);
+            assert_eq!(p.grants[0].original, OsStr::from_bytes(arg));
+        }
+    }
+
+    #[test]
+    fn test_escape_extension() {
+        for escape in ["read", "write", "append", "dir", "mutable-dir"] {
+            let p = do_process(&format!("%{}:/data/in.csv", escape)).unwrap();
+            assert_eq!(p.grants.len(), 1);
+            assert!(p.arg.ends_with(".csv"));
+            assert_eq!(p.grants[0].original, "/data/in.csv");
+        }
+
+        let p = do_process("%read:/data/in.tar.gz").unwrap();
+        assert!(p.arg.ends_with(".tar.gz"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_invalid_extension() {
         use std::os::unix::ffi::OsStrExt;
 
-        let args = [
-            &b"hi/\xc0"[..],
-            &b"hello/\xff"[..],
-            &b"greetings/hello\x80world"[..],
-        ];
-        for arg in args {
-            let p = do_process_os(OsStr::from_bytes(arg)).unwrap();
-            assert_eq!(p.grants.len(), 1);
-            assert_eq!(p.arg, p.grants[0].guest);
-            assert_eq!(p.grants[0].original, OsStr::from_bytes(arg));
-        }
+        let p = do_process_os(OsStr::from_bytes(b"caf\xe9/in.csv")).unwrap();
+        assert_eq!(p.grants.len(), 1);
+        assert!(p.arg.ends_with(".csv"));
+
+        let p = do_process_os(OsStr::from_bytes(b"data/in.\xe9")).unwrap();
+        assert_eq!(p.grants.len(), 1);
+        assert!(!p.arg.contains('\u{fffd}'));
+    }
+
+    #[test]
+    fn test_os_to_os() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args_os_to_os(["foo", "/foo"].map(OsString::from).into_iter())
+            .unwrap();
+        assert_eq!(args[0], "foo");
+        assert_eq!(args[1], OsStr::new(&pathbox.as_slice()[0].guest));
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let args = [
+            "",
+            "foo",
+            "--input=foo",
+            "foo%",
+            "0123:4567:89ab:cdef:0246:8ace:1357:9bdf",
+            "::1",
+            "username@hostname:",
+            "username@hostname:foo",
+            "hostname:",
+            "hostname:foo",
+            "hostname:80",
+            "hostname:/tmp",
+            "username@hostname:/tmp",
+            "https://example.com",
+            "https://example.com:80",
+            "https://example.com/",
+            "https://example.com:80/",
+            // URIs with schemes in `PATHLESS_SCHEMES` are never paths.
+            "data:,Hello%2C%20World!",
+            "data:text/plain;base64,SGVsbG8sIFdvcmxkIQ==",
+            "data:text/plain,/etc/passwd",
+            "DATA:,foo.txt",
+            "tel:+1-201-555-0123",
+            "mailto:someone@example.com",
+            "mailto:a/b.txt",
+            "urn:isbn:0451450523",
+            "urn:foo:/bar/baz.txt",
+            "about:blank",
+            "--input=foo:bar",
+            "--input=foo:/bar",
+            "--input=/foo:bar",
+            ":",
+            "::",
+            "[:alnum:]",
+        ];
+        for arg in args {
+            assert_eq!(do_process(arg), Ok(Process::new(arg, &[])));
+            assert_eq!(
+                do_process(&("%verbatim:".to_owned() + arg)),
+                Ok(Process::new(arg, &[]))
+            );
+        }
+    }
+
+    #[test]
+    fn test_verbatim() {
+        let args = [
+            "/some/arg",
+            "/",
+            "colon/separated:list/of:args/here",
+            "/:/",
+            "--input=/some/arg",
+            "--input=/",
+        ];
+        for arg in args {
+            assert_eq!(
+                do_process(&("%verbatim:".to_owned() + arg)),
+                Ok(Process::new(arg, &[]))
+            );
+
+            let result = do_process(arg).unwrap();
+            assert_ne!(arg, result.arg);
+            assert!(!result.grants.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_paths() {
+        let args = [
+            "/",
+            "./",
+            "//",
+            ".//",
+            ".foo",
+            "foo/bar",
+            "foo/bar/",
+            "foo/bar/",
+            "foo/bar/.",
+            "/foo",
+            "/foo/bar",
+            "/foo/bar/",
+            "/foo/bar/.",
+            "//foo",
+            "//foo/bar",
+            "//foo/bar/",
+            "//foo/bar/.",
+        ];
+        for arg in args {
+            let p = do_process(arg).unwrap();
+            assert_eq!(p.grants.len(), 1);
+            assert_eq!(p.arg, p.grants[0].guest);
+            assert_eq!(p.grants[0].original, arg);
+            assert_eq!(
+                do_process(&("%verbatim:".to_owned() + arg)),
+                Ok(Process::new(arg, &[]))
+            );
+        }
+    }
+
+    #[test]
+    fn test_colon_separated() {
+        // Repeated paths share a grant.
+        let p = do_process("/:/").unwrap();
+        assert_eq!(p.grants.len(), 1);
+        assert_eq!(
+            p.arg,
+            format!("{}:{}", p.grants[0].guest, p.grants[0].guest)
+        );
+        assert_eq!(p.grants[0].original, "/");
+        assert_eq!(do_process("%verbatim:/:/"), Ok(Process::new("/:/", &[])));
+
+        let p = do_process("./foo:./bar").unwrap();
+        assert_eq!(p.grants.len(), 2);
+        assert_eq!(
+            p.arg,
+            format!("{}:{}", p.grants[0].guest, p.grants[1].guest)
+        );
+        assert_eq!(p.grants[0].original, "./foo");
+        assert_eq!(p.grants[1].original, "./bar");
+        assert_eq!(
+            do_process("%verbatim:./foo:./bar"),
+            Ok(Process::new("./foo:./bar", &[]))
+        );
+    }
+
+    #[test]
+    fn test_more_colon_separated() {
+        let name = "name:with:colons";
+        assert_eq!(do_process(name), Ok(Process::new("name:with:colons", &[])));
+
+        let name = "/name:%with/:col/ons";
+        assert_eq!(
+            do_process(name),
+            Ok(Process::new("/name:%with/:col/ons", &[]))
+        );
+
+        let name = "/name:with/:col/ons";
+        let p = do_process(name).unwrap();
+        assert_eq!(
+            p.arg,
+            format!(
+                "{}:{}:{}",
+                p.grants[0].guest, p.grants[1].guest, p.grants[2].guest
+            )
+        );
+    }
+
+    #[test]
+    fn test_readonly() {
+        let mut pathbox = Pathbox::new(MagicLevel::Readonly);
+        let args = pathbox
+            .process_args(
+                [
+                    "input.txt",
+                    "out/",
+                    "%write:output.txt",
+                    "%append:log.txt",
+                    "%mutable-dir:tmp/",
+                ]
+                .map(str::to_owned)
+                .into_iter(),
+            )
+            .unwrap();
+        let accesses = pathbox
+            .as_slice()
+            .iter()
+            .map(|grant| grant.access)
+            .collect::<Vec<_>>();
+        assert_eq!(args.len(), accesses.len());
+        assert_eq!(
+            accesses,
+            [
+                Access::Read,
+                Access::Read,
+                Access::Write,
+                Access::Append,
+                Access::MutableDir
+            ]
+        );
+    }
+
+    #[test]
+    fn test_log_level() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let opened = Arc::new(AtomicUsize::new(0));
+        let mut pathbox = Pathbox::new(MagicLevel::None);
+        let counter = opened.clone();
+        pathbox.set_stderr(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::new(io::sink())
+        });
+
+        pathbox.log(Level::Trace, "test", "message");
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+
+        pathbox.set_log_level(Level::Warning);
+        pathbox.log(Level::Info, "test", "message");
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+        pathbox.log(Level::Error, "test", "message");
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+
+        // At `Off`, nothing is logged, and no stream is even created.
+        pathbox.set_log_level(Level::Off);
+        pathbox.log(Level::Error, "test", "message");
+        pathbox.log(Level::Off, "test", "message");
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_var_names() {
+        let vars = [
+            ("HOME".to_owned(), "/home/user".to_owned()),
+            ("/etc/config.toml".to_owned(), "on".to_owned()),
+        ];
+
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let new_vars = pathbox.process_vars(vars.clone().into_iter()).unwrap();
+        assert_eq!(new_vars[0].0, "HOME");
+        assert_eq!(new_vars[1], vars[1]);
+
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_translate_var_names(true);
+        let new_vars = pathbox.process_vars(vars.into_iter()).unwrap();
+        assert_eq!(new_vars[0].0, "HOME");
+        assert_ne!(new_vars[0].1, "/home/user");
+        assert_eq!(new_vars[1].0, pathbox.as_slice()[1].guest);
+        assert_eq!(pathbox.as_slice()[1].original, "/etc/config.toml");
+        assert_eq!(new_vars[1].1, "on");
+    }
+
+    #[test]
+    fn test_vars_map() {
+        let vars = [
+            ("INPUT".to_owned(), "/data/a.txt".to_owned()),
+            ("MODE".to_owned(), "fast".to_owned()),
+            ("INPUT".to_owned(), "/data/b.txt".to_owned()),
+        ];
+
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let new_vars = pathbox.process_vars_map(vars.into_iter()).unwrap();
+        assert_eq!(new_vars.len(), 2);
+        assert_eq!(new_vars["MODE"], "fast");
+        assert_eq!(new_vars["INPUT"], pathbox.as_slice()[1].guest);
+        assert_eq!(pathbox.as_slice()[1].original, "/data/b.txt");
+
+        // The overwritten value is still granted.
+        assert_eq!(pathbox.as_slice()[0].original, "/data/a.txt");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ill_formed_var_names() {
+        let ill = OsString::from_vec(b"WEIRD\xff".to_vec());
+        let vars = [
+            (OsString::from("A"), OsString::from("/foo")),
+            (ill.clone(), OsString::from("/bar")),
+            (OsString::from("B"), OsString::from("b")),
+        ];
+
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let new_vars = pathbox.process_vars_os(vars.into_iter()).unwrap();
+        assert_eq!(new_vars.len(), 2);
+        assert_eq!(new_vars[0].0, "A");
+        assert_eq!(new_vars[1], ("B".to_owned(), "b".to_owned()));
+        assert_eq!(pathbox.num_grants(), 1);
+
+        // Processing the variable by itself still reports the error.
+        assert!(pathbox.process_var_os((ill, OsString::from("x"))).is_err());
+    }
+
+    #[test]
+    fn test_strict() {
+        let args = ["foo./bar", "--input=foo./bar", "foo/ bar", "a/b=c."];
+        for arg in args {
+            assert_eq!(do_process(arg), Ok(Process::new(arg, &[])));
+
+            let mut pathbox = Pathbox::new(MagicLevel::Auto);
+            pathbox.set_strict(true);
+            assert!(pathbox.process_arg(arg.to_owned()).is_err());
+            assert_eq!(
+                pathbox.process_arg(format!("%verbatim:{}", arg)),
+                Ok(arg.to_owned())
+            );
+        }
+
+        // Flags, URLs, and arguments without slashes aren't ambiguous.
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_strict(true);
+        for arg in ["foo", "-I/usr/include.", "https://example.com", "foo.bar."] {
+            assert_eq!(pathbox.process_arg(arg.to_owned()), Ok(arg.to_owned()));
+        }
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn test_heuristics() {
+        let mut heuristics = Heuristics::new();
+        heuristics.set_allow_trailing_dot_or_space(true);
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_heuristics(heuristics);
+        let arg = pathbox.process_arg("foo./bar".to_owned()).unwrap();
+        assert!(arg.starts_with("guest-path."));
+        assert_eq!(pathbox.as_slice()[0].original, "foo./bar");
+    }
+
+    #[test]
+    fn test_annotated() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args_annotated(
+                [
+                    "-v",
+                    "/foo",
+                    "--input=/bar",
+                    "/a:/b",
+                    "%write:out.txt",
+                    "%verbatim:/baz",
+                    "42",
+                ]
+                .into_iter()
+                .map(str::to_owned),
+            )
+            .unwrap();
+
+        let accesses = args
+            .iter()
+            .map(|(_arg, access)| *access)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            accesses,
+            [
+                None,
+                Some(Access::Any),
+                Some(Access::Any),
+                Some(Access::Any),
+                Some(Access::Write),
+                None,
+                None
+            ]
+        );
+        assert_eq!(args[0].0, "-v");
+        assert!(args[1].0.starts_with("guest-path."));
+        assert_eq!(args[5].0, "/baz");
+        assert_eq!(args[6].0, "42");
+    }
+
+    #[test]
+    fn test_num_grants() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        assert!(pathbox.is_empty());
+        assert_eq!(pathbox.num_grants(), 0);
+        assert_eq!(pathbox.grants().len(), 0);
+
+        let args = pathbox
+            .process_args(["/foo", "-v", "/bar:/baz"].into_iter().map(str::to_owned))
+            .unwrap();
+        assert!(!pathbox.is_empty());
+        assert_eq!(pathbox.num_grants(), 3);
+        let guests = pathbox
+            .grants()
+            .map(|g| g.guest.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(guests[0], args[0]);
+        assert_eq!(guests[1..].join(":"), args[2]);
+    }
+
+    #[test]
+    fn test_canonical_token() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(
+                ["/foo", "%read-range:0-1:/bar"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+        assert_eq!(pathbox.canonical_token("/foo"), Some(args[0].clone()));
+        assert_eq!(pathbox.canonical_token("/foo/"), None);
+        assert_eq!(pathbox.canonical_token("/bar"), None);
+        assert_eq!(pathbox.num_grants(), 2);
+
+        #[cfg(unix)]
+        {
+            let original = OsString::from_vec(b"/\xff.txt".to_vec());
+            let arg = pathbox.process_arg_os(original.clone()).unwrap();
+            assert_eq!(pathbox.canonical_token(&original), Some(arg));
+            assert_eq!(pathbox.canonical_token("/\u{fffd}.txt"), None);
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox
+            .process_args(["/foo", "%dir:/bar"].into_iter().map(str::to_owned))
+            .unwrap();
+        assert_eq!(pathbox.validate(), Ok(()));
+
+        pathbox.process_arg("%read:".to_owned()).unwrap();
+        assert_eq!(pathbox.validate().unwrap_err().len(), 1);
+
+        let var_name = "PATHBOX_TEST_VALIDATE";
+        std::env::set_var(
+            var_name,
+            "read guest-path.a /a\nread:0-1 guest-path.ab /b\nwrite guest-path.c %00",
+        );
+        let pathbox = Pathbox::from_env(var_name, MagicLevel::None).unwrap();
+        std::env::remove_var(var_name);
+        let errors = pathbox.validate().unwrap_err();
+        assert_eq!(errors.len(), if cfg!(unix) { 2 } else { 1 });
+    }
+
+    #[test]
+    fn test_stdin() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(
+                ["%read:-", "-", "%read:./-", "%verbatim:-"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+
+        // All stdin tokens share the one grant.
+        assert_eq!(args[0], args[1]);
+        assert_ne!(args[0], args[2]);
+        assert_eq!(args[3], "-");
+        assert_eq!(pathbox.num_grants(), 2);
+        assert!(pathbox.as_slice()[0].stdin);
+        assert_eq!(pathbox.as_slice()[0].original, "-");
+        assert!(!pathbox.as_slice()[1].stdin);
+
+        pathbox.open(&args[0]).unwrap();
+        assert_eq!(
+            pathbox
+                .host_path(&args[0], Access::Read)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(pathbox.canonical_token("-"), None);
+
+        // Without path inference, a plain `-` is passed through.
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        assert_eq!(pathbox.process_arg("-".to_owned()).unwrap(), "-");
+        assert!(pathbox.is_empty());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_drive_paths() {
+        for path in ["C:foo", r"C:\foo", "C:"] {
+            let mut pathbox = Pathbox::new(MagicLevel::Auto);
+            let arg = pathbox.process_arg(path.to_owned()).unwrap();
+            assert_eq!(pathbox.num_grants(), 1);
+            assert_eq!(arg, pathbox.as_slice()[0].guest);
+            assert_eq!(pathbox.as_slice()[0].original, path);
+        }
+    }
+
+    #[test]
+    fn test_forbid_absolute() {
+        let mut pathbox = Pathbox::new(MagicLevel::Readonly);
+        pathbox.set_forbid_absolute(true);
+        pathbox.process_arg("foo/bar.txt".to_owned()).unwrap();
+        assert_eq!(pathbox.num_grants(), 1);
+        pathbox.process_arg("/foo/bar.txt".to_owned()).unwrap_err();
+        pathbox
+            .process_arg("--input=/foo/bar.txt".to_owned())
+            .unwrap_err();
+        pathbox.process_arg("/a.txt:b.txt".to_owned()).unwrap_err();
+        assert_eq!(pathbox.num_grants(), 1);
+
+        // Relative paths before an absolute one aren't granted either.
+        pathbox.process_arg("b.txt:/a.txt".to_owned()).unwrap_err();
+        assert_eq!(pathbox.num_grants(), 1);
+        assert_eq!(pathbox.translate_in("b.txt"), None);
+
+        // Explicit escapes are still permitted.
+        pathbox
+            .process_arg("%read:/foo/bar.txt".to_owned())
+            .unwrap();
+        assert_eq!(pathbox.num_grants(), 2);
+    }
+
+    #[test]
+    fn test_glued_path_flags() {
+        // By default, glued paths aren't recognized.
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let arg = pathbox.process_arg("-I/usr/include".to_owned()).unwrap();
+        assert_eq!(arg, "-I/usr/include");
+        assert!(pathbox.is_empty());
+
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_glued_path_flags(&["-I", "-L", "-o"]);
+        let arg = pathbox.process_arg("-I/usr/include".to_owned()).unwrap();
+        assert_eq!(arg, format!("-I{}", pathbox.as_slice()[0].guest));
+        assert_eq!(pathbox.as_slice()[0].original, "/usr/include");
+
+        // The remainder is a path even if the heuristics wouldn't say so.
+        let arg = pathbox.process_arg("-oout".to_owned()).unwrap();
+        assert_eq!(arg, format!("-o{}", pathbox.as_slice()[1].guest));
+        assert_eq!(pathbox.as_slice()[1].original, "out");
+
+        // A flag on its own, and other flags, are left alone.
+        assert_eq!(pathbox.process_arg("-o".to_owned()).unwrap(), "-o");
+        assert_eq!(pathbox.process_arg("-W/x".to_owned()).unwrap(), "-W/x");
+        assert_eq!(pathbox.num_grants(), 2);
+    }
+
+    #[test]
+    fn test_path_flags() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_path_flags(&[("-o", Access::Write)]);
+        let args = pathbox
+            .process_args_annotated(
+                ["-o", "out", "-o", "%read:in", "-o", "-o", "out2"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+        assert_eq!(args[0], ("-o".to_owned(), None));
+        assert_eq!(args[1].1, Some(Access::Write));
+        assert_eq!(pathbox.as_slice()[0].original, "out");
+        assert_eq!(pathbox.as_slice()[0].access, Access::Write);
+
+        // Escapes still apply to the following argument.
+        assert_eq!(args[3].1, Some(Access::Read));
+
+        // A flag following a flag is a path.
+        assert_eq!(args[5].1, Some(Access::Write));
+        assert_eq!(pathbox.as_slice()[2].original, "-o");
+        assert_eq!(args[6], ("out2".to_owned(), None));
+
+        // The access is limited by the magic level.
+        let mut pathbox = Pathbox::new(MagicLevel::Readonly);
+        pathbox.set_path_flags(&[("-o", Access::Write)]);
+        pathbox
+            .process_args(["-o", "out"].into_iter().map(str::to_owned))
+            .unwrap();
+        assert_eq!(pathbox.as_slice()[0].access, Access::Read);
+
+        // A trailing flag doesn't apply to environment variables.
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_path_flags(&[("-o", Access::Write)]);
+        pathbox.process_arg("-o".to_owned()).unwrap();
+        let (_key, val) = pathbox
+            .process_var(("KEY".to_owned(), "value".to_owned()))
+            .unwrap();
+        assert_eq!(val, "value");
+        assert!(pathbox.is_empty());
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        let normalize = |path: &str, dot_dot| {
+            String::from_utf8(normalize_path(path.as_bytes(), dot_dot)).unwrap()
+        };
+        assert_eq!(normalize("foo//./bar", false), "foo/bar");
+        assert_eq!(normalize("./foo", false), "foo");
+        assert_eq!(normalize("foo/.", false), "foo");
+        assert_eq!(normalize("foo/", false), "foo/");
+        assert_eq!(normalize(".", false), ".");
+        assert_eq!(normalize("./", false), ".");
+        assert_eq!(normalize("/", false), "/");
+        assert_eq!(normalize("/./", false), "/");
+        assert_eq!(normalize("//server/share", false), "//server/share");
+        assert_eq!(normalize("///foo", false), "/foo");
+        assert_eq!(normalize("foo/../bar", false), "foo/../bar");
+
+        assert_eq!(normalize("foo/../bar", true), "bar");
+        assert_eq!(normalize("foo/..", true), ".");
+        assert_eq!(normalize("../foo/../..", true), "../..");
+        assert_eq!(normalize("/../foo", true), "/foo");
+    }
+
+    #[test]
+    fn test_normalize_paths() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_normalize_paths(true);
+        let a = pathbox.process_arg("./foo//bar.txt".to_owned()).unwrap();
+        let b = pathbox.process_arg("foo/./bar.txt".to_owned()).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(pathbox.num_grants(), 1);
+        assert_eq!(pathbox.as_slice()[0].original, "foo/bar.txt");
+
+        pathbox.process_arg("foo/../baz.txt".to_owned()).unwrap();
+        assert_eq!(pathbox.as_slice()[1].original, "foo/../baz.txt");
+
+        pathbox.set_normalize_dot_dot(true);
+        pathbox.process_arg("foo/../qux.txt".to_owned()).unwrap();
+        assert_eq!(pathbox.as_slice()[2].original, "qux.txt");
+    }
+
+    #[test]
+    fn test_fold_windows_path() {
+        assert_eq!(
+            fold_windows_path(r"C:\Foo\bar"),
+            fold_windows_path(r"c:\foo\BAR")
+        );
+        assert_eq!(
+            fold_windows_path("C:/Foo/bar"),
+            fold_windows_path(r"c:\foo\bar")
+        );
+        assert_eq!(
+            fold_windows_path(r"\\?\C:\foo"),
+            fold_windows_path(r"C:\foo")
+        );
+        assert_ne!(fold_windows_path(r"\\?\UNC\x"), fold_windows_path(r"UNC\x"));
+        assert_ne!(fold_windows_path(r"C:\foo"), fold_windows_path(r"C:\foo2"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_case_insensitive_merge() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let a = pathbox.process_arg(r"C:\Foo\bar.txt".to_owned()).unwrap();
+        let b = pathbox.process_arg(r"c:\foo\BAR.txt".to_owned()).unwrap();
+        let c = pathbox.process_arg("C:/Foo/bar.txt".to_owned()).unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a, c);
+        assert_eq!(pathbox.num_grants(), 1);
+    }
+
+    #[test]
+    fn test_url_path() {
+        let p = do_process("%url-path:%2Fhome%2Fuser%2Ffile%20name.txt").unwrap();
+        assert_eq!(p.grants.len(), 1);
+        assert_eq!(p.grants[0].original, "/home/user/file name.txt");
+        assert_eq!(p.grants[0].access, Access::Read);
+        assert_eq!(p.arg, p.grants[0].guest);
+        assert!(p.arg.ends_with(".txt"));
+
+        // Unencoded characters are passed through.
+        let p = do_process("%url-path:/a/b.txt").unwrap();
+        assert_eq!(p.grants[0].original, "/a/b.txt");
+
+        do_process("%url-path:%2").unwrap_err();
+        do_process("%url-path:%zz").unwrap_err();
+        do_process("%url-path:%+1").unwrap_err();
+        do_process("%url-path:/a%00b").unwrap_err();
+    }
+
+    #[test]
+    fn test_limit_escapes() {
+        let mut pathbox = Pathbox::new(MagicLevel::Readonly);
+        pathbox.process_arg("%write:/foo.txt".to_owned()).unwrap();
+        assert_eq!(pathbox.as_slice()[0].access, Access::Write);
+
+        let mut pathbox = Pathbox::new(MagicLevel::Readonly);
+        pathbox.set_limit_escapes(true);
+        for arg in [
+            "%write:/foo.txt",
+            "%append:/foo.txt",
+            "%rwc:/foo.txt",
+            "%mutable-dir:/foo",
+            "%temp-dir:",
+            "%mutable-cwd",
+        ] {
+            pathbox.process_arg(arg.to_owned()).unwrap_err();
+        }
+        assert!(pathbox.is_empty());
+        for arg in ["%read:/foo.txt", "%dir:/foo", "%cwd", "bar.txt"] {
+            pathbox.process_arg(arg.to_owned()).unwrap();
+        }
+        assert_eq!(pathbox.num_grants(), 4);
+
+        // Other magic levels aren't limited.
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        pathbox.set_limit_escapes(true);
+        pathbox.process_arg("%write:/foo.txt".to_owned()).unwrap();
+    }
+
+    #[test]
+    fn test_readable_tokens() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_readable_tokens(true);
+        let args = pathbox
+            .process_args(
+                ["/data/in put.txt", "out/", "/a/b", "/data/in put.txt"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+        assert_eq!(
+            args,
+            [
+                "guest-path.1-in_put.txt",
+                "guest-path.2-out",
+                "guest-path.3-b",
+                "guest-path.1-in_put.txt",
+            ]
+        );
+        assert_eq!(pathbox.translate_in("/data/in put.txt").unwrap(), args[0]);
+
+        // Repeated paths don't use up numbers.
+        let arg = pathbox.process_arg("/d.txt".to_owned()).unwrap();
+        assert_eq!(arg, "guest-path.4-d.txt");
+
+        // Counters which are in use, such as by inherited grants, are skipped.
+        let mut child = Pathbox::new(MagicLevel::Auto);
+        child.set_readable_tokens(true);
+        child.grants = pathbox.grants.clone();
+        let arg = child.process_arg("/c.txt".to_owned()).unwrap();
+        assert_eq!(arg, "guest-path.5-c.txt");
+    }
+
+    #[test]
+    fn test_readable_token_separator() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_readable_tokens(true);
+        pathbox.set_readable_token_separator('~');
+        let args = pathbox
+            .process_args(
+                ["/data/v1.2.tar.gz", "/data/my.notes.txt.bak"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+        assert_eq!(
+            args,
+            ["guest-path.1~v1.2.tar.gz", "guest-path.2~my.notes.txt.bak"]
+        );
+        assert_eq!(
+            Pathbox::split_guest_extension(&args[0]),
+            ("guest-path.1~v1", ".2.tar.gz")
+        );
+        assert_eq!(
+            Pathbox::split_guest_extension(&args[1]),
+            ("guest-path.2~my", ".notes.txt.bak")
+        );
+    }
+
+    #[test]
+    fn test_split_guest_extension() {
+        assert_eq!(
+            Pathbox::split_guest_extension("guest-path.1-input.tar.gz"),
+            ("guest-path.1-input", ".tar.gz")
+        );
+        assert_eq!(
+            Pathbox::split_guest_extension("guest-path.1-input"),
+            ("guest-path.1-input", "")
+        );
+        assert_eq!(Pathbox::split_guest_extension("foo.txt"), ("foo.txt", ""));
+    }
+
+    #[test]
+    fn test_non_executable_extensions() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        pathbox
+            .process_arg("%read-exec:/notes.txt".to_owned())
+            .unwrap_err();
+        pathbox
+            .process_arg("%read-exec:/README.MD".to_owned())
+            .unwrap_err();
+        pathbox
+            .process_arg("%read-exec:/run.sh".to_owned())
+            .unwrap();
+        pathbox.process_arg("%read-exec:/run".to_owned()).unwrap();
+
+        // Upgrading is checked too.
+        let token = pathbox.process_arg("%read:/data.json".to_owned()).unwrap();
+        pathbox.upgrade(&token, Access::ReadExec).unwrap_err();
+        pathbox.upgrade(&token, Access::Write).unwrap();
+
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        pathbox.set_non_executable_extensions(&["sh"]);
+        pathbox
+            .process_arg("%read-exec:/notes.txt".to_owned())
+            .unwrap();
+        pathbox
+            .process_arg("%read-exec:/run.sh".to_owned())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_nul() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        pathbox
+            .process_arg("%read:foo\0bar".to_owned())
+            .unwrap_err();
+        pathbox.process_arg("%write:\0".to_owned()).unwrap_err();
+        pathbox
+            .process_arg_os(OsString::from("%dir:/foo\0"))
+            .unwrap_err();
+        assert!(pathbox.is_empty());
+    }
+
+    #[test]
+    fn test_hide_host_path() {
+        let host = Path::new("/secret/dir/file.txt");
+        let err = io::Error::new(
+            io::ErrorKind::NotFound,
+            "failed to open /secret/dir/file.txt: not found",
+        );
+        let err = hide_host_path(err, host, "guest-path.x.txt");
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(
+            err.to_string(),
+            "failed to open guest-path.x.txt: not found"
+        );
+
+        // Errors which don't mention the path are returned as is.
+        let err = hide_host_path(io::Error::from_raw_os_error(2), host, "guest-path.x.txt");
+        assert_eq!(err.raw_os_error(), Some(2));
+    }
+
+    #[test]
+    fn test_retry_on_interrupt() {
+        let mut calls = 0;
+        let result = retry_on_interrupt(|| {
+            calls += 1;
+            if calls < 3 {
+                Err(io::Error::from(io::ErrorKind::Interrupted))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let result: io::Result<()> =
+            retry_on_interrupt(|| Err(io::Error::from(io::ErrorKind::NotFound)));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_grant_order() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let args = pathbox
+            .process_args(
+                [
+                    "%read:/b.txt",
+                    "%read:/a.txt",
+                    "%write:/b.txt",
+                    "%read:-",
+                    "%read:/c.txt",
+                    "%write:/a.txt",
+                    "%read:-",
+                ]
+                .into_iter()
+                .map(str::to_owned),
+            )
+            .unwrap();
+        let guests: Vec<_> = pathbox.grants().map(|g| g.guest.as_str()).collect();
+        assert_eq!(guests, [&args[0], &args[1], &args[3], &args[4]]);
+        let originals: Vec<_> = pathbox.grants().map(|g| g.original.clone()).collect();
+        assert_eq!(originals, ["/b.txt", "/a.txt", "-", "/c.txt"]);
+        assert_eq!(pathbox.as_slice()[0].access, Access::ReadWrite);
+        assert_eq!(pathbox.as_slice()[1].access, Access::ReadWrite);
+
+        let preopens: Vec<_> = pathbox
+            .preopens()
+            .map(|p| (p.token(), p.original(), p.access()))
+            .collect();
+        assert_eq!(
+            preopens,
+            [
+                (args[0].as_str(), OsStr::new("/b.txt"), Access::ReadWrite),
+                (args[1].as_str(), OsStr::new("/a.txt"), Access::ReadWrite),
+                (args[3].as_str(), OsStr::new("-"), Access::Read),
+                (args[4].as_str(), OsStr::new("/c.txt"), Access::Read),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_quoted_words() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let arg = "--exec=\"cat /etc/hosts\"";
+        assert_eq!(pathbox.process_arg(arg.to_owned()).unwrap(), arg);
+        assert!(pathbox.is_empty());
+
+        pathbox.set_translate_quoted_words(true);
+        let translated = pathbox.process_arg(arg.to_owned()).unwrap();
+        let token = pathbox.translate_in("/etc/hosts").unwrap();
+        assert_eq!(translated, format!("--exec=\"cat {}\"", token));
+
+        let translated = pathbox
+            .process_arg("-c 'diff  a.txt\tb.txt' \"x\" 'unterminated /c.txt".to_owned())
+            .unwrap();
+        let a = pathbox.translate_in("a.txt").unwrap();
+        let b = pathbox.translate_in("b.txt").unwrap();
+        assert_eq!(
+            translated,
+            format!("-c 'diff  {}\t{}' \"x\" 'unterminated /c.txt", a, b)
+        );
+        assert_eq!(pathbox.translate_in("/c.txt"), None);
+
+        // Arguments without paths in quotes are processed as usual.
+        let arg = pathbox.process_arg("\"x\"".to_owned()).unwrap();
+        assert_eq!(arg, "\"x\"");
+        let translated = pathbox.process_arg("/d.txt".to_owned()).unwrap();
+        assert_eq!(Some(translated), pathbox.translate_in("/d.txt"));
+    }
+
+    #[test]
+    fn test_into_grants() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let args = pathbox
+            .process_args(
+                ["%read:/a.txt", "%write:/b.txt"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+        let expected = pathbox.as_slice().to_vec();
+        let grants = pathbox.into_grants();
+        assert_eq!(grants, expected);
+        assert_eq!(grants[0].guest, args[0]);
+        assert_eq!(grants[1].original, "/b.txt");
+        assert_eq!(grants[1].access, Access::Write);
+    }
+
+    #[test]
+    fn test_idempotent() {
+        let args = [
+            "/a/b.txt",
+            "--out=/c/d.txt",
+            "/x.txt:/y.txt",
+            "-I/include",
+            "-o",
+            "out",
+            "-",
+            "%read:/e.txt",
+            "%dir:/f",
+            "plain",
+        ];
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_glued_path_flags(&["-I"]);
+        pathbox.set_path_flags(&[("-o", Access::Write)]);
+        let once = pathbox
+            .process_args(args.into_iter().map(str::to_owned))
+            .unwrap();
+        let num_grants = pathbox.num_grants();
+
+        let twice = pathbox.process_args(once.clone().into_iter()).unwrap();
+        assert_eq!(twice, once);
+        let sub = format!("{}/g.txt", once[8]);
+        assert_eq!(pathbox.process_arg(sub.clone()).unwrap(), sub);
+        assert_eq!(pathbox.num_grants(), num_grants);
+    }
+
+    #[test]
+    fn test_expand_env_vars() {
+        std::env::set_var("PATHBOX_TEST_DIR", "/data");
+        std::env::set_var("PATHBOX_TEST_WORD", "word");
+        std::env::remove_var("PATHBOX_TEST_UNDEFINED");
+
+        assert_eq!(
+            expand_env_vars("$PATHBOX_TEST_DIR/a:${PATHBOX_TEST_DIR}b").unwrap(),
+            "/data/a:/datab"
+        );
+        assert_eq!(expand_env_vars("a$ $1 ${}").unwrap(), "a$ $1 ${}");
+        expand_env_vars("$PATHBOX_TEST_UNDEFINED/a").unwrap_err();
+        expand_env_vars("${PATHBOX_TEST_DIR").unwrap_err();
+
+        // By default, variables aren't expanded.
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox
+            .process_arg("$PATHBOX_TEST_DIR/in.txt".to_owned())
+            .unwrap();
+        assert_eq!(pathbox.as_slice()[0].original, "$PATHBOX_TEST_DIR/in.txt");
+
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_expand_env_vars(true);
+        let arg = pathbox
+            .process_arg("${PATHBOX_TEST_DIR}/in.txt".to_owned())
+            .unwrap();
+        assert_eq!(pathbox.translate_in("/data/in.txt"), Some(arg));
+
+        // Arguments which aren't paths aren't expanded, and escapes aren't
+        // expanded at all.
+        let arg = pathbox
+            .process_arg("x$PATHBOX_TEST_WORD".to_owned())
+            .unwrap();
+        assert_eq!(arg, "x$PATHBOX_TEST_WORD");
+        pathbox
+            .process_arg("%read:$PATHBOX_TEST_DIR".to_owned())
+            .unwrap();
+        assert_eq!(pathbox.as_slice()[1].original, "$PATHBOX_TEST_DIR");
+        pathbox
+            .process_arg("$PATHBOX_TEST_UNDEFINED/in.txt".to_owned())
+            .unwrap_err();
+
+        // The length limit applies to the expansion too.
+        std::env::set_var("PATHBOX_TEST_LONG", "/long".repeat(20));
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_expand_env_vars(true);
+        pathbox.set_max_arg_bytes(64);
+        let arg = pathbox
+            .process_arg("$PATHBOX_TEST_LONG/in.txt".to_owned())
+            .unwrap();
+        assert_eq!(arg, "$PATHBOX_TEST_LONG/in.txt");
+        assert!(pathbox.is_empty());
+        pathbox.set_strict(true);
+        pathbox
+            .process_arg("$PATHBOX_TEST_LONG/in.txt".to_owned())
+            .unwrap_err();
+
+        // An expansion which isn't used doesn't act as a flag like `-o`.
+        std::env::set_var("PATHBOX_TEST_FLAG", "-o");
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_expand_env_vars(true);
+        pathbox.set_path_flags(&[("-o", Access::Write)]);
+        let args = pathbox
+            .process_args(
+                ["$PATHBOX_TEST_FLAG", "plain"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+        assert_eq!(args, ["$PATHBOX_TEST_FLAG", "plain"]);
+        assert!(pathbox.is_empty());
     }
 
     #[test]
-    fn test_passthrough() {
-        let args = [
-            "",
-            "foo",
-            "--input=foo",
-            "foo%",
-            "0123:4567:89ab:cdef:0246:8ace:1357:9bdf",
-            "::1",
-            "username@hostname:",
-            "username@hostname:foo",
-            "hostname:",
-            "hostname:foo",
-            "hostname:80",
-            "hostname:/tmp",
-            "username@hostname:/tmp",
-            "https://example.com",
-            "https://example.com:80",
-            "https://example.com/",
-            "https://example.com:80/",
-            "data:,Hello%2C%20World!",
-            "data:text/plain;base64,SGVsbG8sIFdvcmxkIQ==",
-            "--input=foo:bar",
-            "--input=foo:/bar",
-            "--input=/foo:bar",
-            ":",
-            "::",
-            "[:alnum:]",
-        ];
-        for arg in args {
-            assert_eq!(do_process(arg), Ok(Process::new(arg, &[])));
+    fn test_max_arg_bytes() {
+        let list = ["/a.txt"; 4].join(":");
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_max_arg_bytes(list.len());
+        assert_ne!(pathbox.process_arg(list.clone()).unwrap(), list);
+        assert_eq!(pathbox.num_grants(), 1);
+
+        let long = list.clone() + ":/b.txt";
+        assert_eq!(pathbox.process_arg(long.clone()).unwrap(), long);
+        assert_eq!(pathbox.num_grants(), 1);
+
+        // Escapes aren't limited.
+        pathbox.process_arg(format!("%read:{}", long)).unwrap();
+        assert_eq!(pathbox.num_grants(), 2);
+
+        pathbox.set_strict(true);
+        pathbox.process_arg(long).unwrap_err();
+        pathbox.process_arg(list).unwrap();
+    }
+
+    #[test]
+    fn test_guest_shape() {
+        assert!(has_guest_shape("guest-path.x"));
+        assert!(has_guest_shape("guest-path.1-input.txt/foo"));
+        assert!(!has_guest_shape("foo"));
+        assert!(!has_guest_shape("guest-path."));
+        assert!(!has_guest_shape("guest-path./etc/passwd"));
+        assert!(!has_guest_shape("/guest-path.x"));
+
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let arg = pathbox.process_arg("%read:/foo.txt".to_owned()).unwrap();
+        assert_eq!(
+            pathbox.host_path(&arg, Access::Read).unwrap(),
+            PathBuf::from("/foo.txt")
+        );
+        for path in ["foo", "guest-path.", "guest-path./foo.txt"] {
             assert_eq!(
-                do_process(&("%verbatim:".to_owned() + arg)),
-                Ok(Process::new(arg, &[]))
+                pathbox.host_path(path, Access::Read).unwrap_err().kind(),
+                io::ErrorKind::NotFound
             );
         }
     }
 
     #[test]
-    fn test_verbatim() {
-        let args = [
-            "/some/arg",
-            "/",
-            "colon/separated:list/of:args/here",
-            "/:/",
-            "--input=/some/arg",
-            "--input=/",
-        ];
-        for arg in args {
+    fn test_partial_colon_lists() {
+        // By default, lists are translated all or nothing.
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let all = pathbox.process_arg("/a:/b".to_owned()).unwrap();
+        assert_eq!(pathbox.num_grants(), 2);
+        assert_eq!(
+            all,
+            format!(
+                "{}:{}",
+                pathbox.as_slice()[0].guest,
+                pathbox.as_slice()[1].guest
+            )
+        );
+        let mixed = pathbox.process_arg("/a:notapath".to_owned()).unwrap();
+        assert_eq!(mixed, "/a:notapath");
+        assert_eq!(pathbox.num_grants(), 2);
+
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_partial_colon_lists(true);
+        let all = pathbox.process_arg("/a:/b".to_owned()).unwrap();
+        assert_eq!(pathbox.num_grants(), 2);
+        assert_eq!(
+            all,
+            format!(
+                "{}:{}",
+                pathbox.as_slice()[0].guest,
+                pathbox.as_slice()[1].guest
+            )
+        );
+        let mixed = pathbox.process_arg("/a:notapath".to_owned()).unwrap();
+        assert_eq!(mixed, format!("{}:notapath", pathbox.as_slice()[0].guest));
+        let none = pathbox.process_arg("host:port".to_owned()).unwrap();
+        assert_eq!(none, "host:port");
+        assert_eq!(pathbox.num_grants(), 2);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_max_grants(2);
+        pathbox
+            .process_args(["/a", "/b", "/a"].into_iter().map(str::to_owned))
+            .unwrap();
+        assert!(pathbox.process_arg("/c".to_owned()).is_err());
+        assert!(pathbox.process_arg("/c:/d".to_owned()).is_err());
+        assert!(pathbox.process_arg("%write:/c".to_owned()).is_err());
+        assert_eq!(pathbox.num_grants(), 2);
+
+        // When a later path in an argument exceeds the limit, the earlier
+        // ones aren't granted either.
+        pathbox.set_max_grants(3);
+        assert!(pathbox.process_arg("/c:/d".to_owned()).is_err());
+        assert!(pathbox.process_arg("/c=/d".to_owned()).is_err());
+        assert_eq!(pathbox.num_grants(), 2);
+        assert_eq!(pathbox.translate_in("/c"), None);
+
+        // Repeated paths and non-paths don't count.
+        pathbox
+            .process_args(["/b", "-v", "%read:/a"].into_iter().map(str::to_owned))
+            .unwrap();
+
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_max_path_bytes(10);
+        pathbox.process_arg("/abc:/def".to_owned()).unwrap();
+        assert!(pathbox.process_arg("/ghi".to_owned()).is_err());
+        pathbox.process_arg("/g".to_owned()).unwrap();
+        assert_eq!(pathbox.num_grants(), 3);
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let args = pathbox
+            .process_args(
+                ["%read:/a", "%dir:/b", "%read-range:0-1:/c", "%read:-"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+
+        pathbox.upgrade(&args[0], Access::Write).unwrap();
+        assert_eq!(pathbox.as_slice()[0].access, Access::ReadWrite);
+        pathbox.host_path(&args[0], Access::Write).unwrap();
+
+        // Upgrading never narrows.
+        pathbox.upgrade(&args[0], Access::Read).unwrap();
+        assert_eq!(pathbox.as_slice()[0].access, Access::ReadWrite);
+        pathbox.upgrade(&args[1], Access::MutableDir).unwrap();
+        assert_eq!(pathbox.as_slice()[1].access, Access::MutableDir);
+        pathbox.upgrade(&args[1], Access::ReadonlyDir).unwrap();
+        assert_eq!(pathbox.as_slice()[1].access, Access::MutableDir);
+
+        // Restricted grants can't be widened.
+        pathbox.upgrade(&args[2], Access::Read).unwrap();
+        assert!(pathbox.upgrade(&args[2], Access::Write).is_err());
+        assert!(pathbox.upgrade(&args[3], Access::ReadWrite).is_err());
+        assert_eq!(pathbox.as_slice()[2].access, Access::Read);
+        assert_eq!(pathbox.as_slice()[3].access, Access::Read);
+
+        assert!(pathbox.upgrade("guest-path.x", Access::Read).is_err());
+    }
+
+    #[test]
+    fn test_dotfile_extension() {
+        for (original, ext) in [
+            (".env.local", ".local"),
+            (".config.json", ".json"),
+            (".a.b.c", ".b.c"),
+            ("/foo/.env.local", ".local"),
+            (".gitignore", ""),
+        ] {
+            let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+            let token = pathbox.process_arg(format!("%read:{}", original)).unwrap();
             assert_eq!(
-                do_process(&("%verbatim:".to_owned() + arg)),
-                Ok(Process::new(arg, &[]))
+                token.strip_prefix("guest-path.").unwrap().len(),
+                36 + ext.len()
+            );
+            assert!(
+                token.ends_with(ext),
+                "{:?} should end with {:?}",
+                token,
+                ext
             );
 
-            let result = do_process(arg).unwrap();
-            assert_ne!(arg, result.arg);
-            assert!(!result.grants.is_empty());
+            // The original is recovered exactly.
+            assert_eq!(
+                pathbox.host_path(&token, Access::Read).unwrap(),
+                PathBuf::from(original)
+            );
+            let mut buf = token.into_bytes();
+            crate::writer::replace_guest_paths(&pathbox, &mut buf);
+            assert_eq!(buf, original.as_bytes());
         }
     }
 
     #[test]
-    fn test_paths() {
-        let args = [
-            "/",
-            "./",
-            "//",
-            ".//",
-            ".foo",
-            "foo/bar",
-            "foo/bar/",
-            "foo/bar/",
-            "foo/bar/.",
-            "/foo",
-            "/foo/bar",
-            "/foo/bar/",
-            "/foo/bar/.",
-            "//foo",
-            "//foo/bar",
-            "//foo/bar/",
-            "//foo/bar/.",
+    fn test_resolve() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let args = pathbox
+            .process_args(["%dir:/a/b"].into_iter().map(str::to_owned))
+            .unwrap();
+
+        // A whole token borrows the original path; a subpath is joined.
+        let whole = pathbox.resolve(&args[0], Access::ReadonlyDir).unwrap();
+        assert!(matches!(whole, Cow::Borrowed(_)));
+        assert_eq!(whole, Path::new("/a/b"));
+        let sub = pathbox
+            .resolve(&format!("{}/c", args[0]), Access::ReadonlyDir)
+            .unwrap();
+        assert!(matches!(sub, Cow::Owned(_)));
+        assert_eq!(sub, Path::new("/a/b/c"));
+    }
+
+    #[test]
+    fn test_overlapping_guests() {
+        let grant = |guest: &str, original: &str, access| Grant {
+            guest: guest.to_owned(),
+            original: original.into(),
+            access,
+            range: None,
+            stdin: false,
+        };
+        let grants = [
+            grant("guest-path.a", "/dir", Access::ReadonlyDir),
+            grant("guest-path.a/sub", "/other", Access::ReadonlyDir),
+            grant("guest-path.ab", "/ab.txt", Access::Read),
+            grant("guest-path.a/file", "/file.txt", Access::Read),
         ];
-        for arg in args {
-            let p = do_process(arg).unwrap();
-            assert_eq!(p.grants.len(), 1);
-            assert_eq!(p.arg, p.grants[0].guest);
-            assert_eq!(p.grants[0].original, arg);
+
+        // The result is the same regardless of the order of the grants.
+        for reverse in [false, true] {
+            let mut pathbox = Pathbox::new(MagicLevel::None);
+            pathbox.grants = grants.to_vec();
+            if reverse {
+                pathbox.grants.reverse();
+            }
+            let resolve = |path| pathbox.host_path(path, Access::ReadonlyDir).unwrap();
+            assert_eq!(resolve("guest-path.a"), Path::new("/dir"));
+            assert_eq!(resolve("guest-path.a/x"), Path::new("/dir/x"));
+            assert_eq!(resolve("guest-path.a/sub"), Path::new("/other"));
+            assert_eq!(resolve("guest-path.a/sub/x"), Path::new("/other/x"));
+            assert_eq!(resolve("guest-path.a/subx"), Path::new("/dir/subx"));
+
+            // A guest path only matches whole components.
             assert_eq!(
-                do_process(&("%verbatim:".to_owned() + arg)),
-                Ok(Process::new(arg, &[]))
+                pathbox.host_path("guest-path.ab", Access::Read).unwrap(),
+                Path::new("/ab.txt")
+            );
+            assert_eq!(
+                pathbox
+                    .host_path("guest-path.abc", Access::Read)
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::NotFound
+            );
+            assert_eq!(
+                pathbox
+                    .host_path("guest-path.ab", Access::Write)
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::PermissionDenied
+            );
+
+            // The longest match is used even if it doesn't permit the
+            // access, rather than falling back to a shorter one.
+            assert_eq!(
+                pathbox
+                    .host_path("guest-path.a/file", Access::ReadonlyDir)
+                    .unwrap_err()
+                    .kind(),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                pathbox
+                    .host_path("guest-path.a/file", Access::Read)
+                    .unwrap(),
+                Path::new("/file.txt")
             );
         }
     }
 
     #[test]
-    fn test_colon_separated() {
-        let p = do_process("/:/").unwrap();
-        assert_eq!(p.grants.len(), 2);
+    fn test_translate_in() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let args = pathbox
+            .process_args(
+                [
+                    "%dir:/a/b",
+                    "%dir:/a",
+                    "%read:/a/b/c/file.txt",
+                    "%read:/x.txt",
+                ]
+                .into_iter()
+                .map(str::to_owned),
+            )
+            .unwrap();
+
+        // The most specific grant wins, regardless of the order of grants.
+        assert_eq!(pathbox.translate_in("/a"), Some(args[1].clone()));
+        assert_eq!(pathbox.translate_in("/a/b"), Some(args[0].clone()));
+        assert_eq!(pathbox.translate_in("/a/b/"), Some(args[0].clone()));
+        assert_eq!(pathbox.translate_in("/a/c"), Some(format!("{}/c", args[1])));
         assert_eq!(
-            p.arg,
-            format!("{}:{}", p.grants[0].guest, p.grants[1].guest)
+            pathbox.translate_in("/a/b/c/d.txt"),
+            Some(format!("{}/c/d.txt", args[0]))
         );
-        assert_eq!(p.grants[0].original, "/");
-        assert_eq!(p.grants[1].original, "/");
-        assert_eq!(do_process("%verbatim:/:/"), Ok(Process::new("/:/", &[])));
+        assert_eq!(
+            pathbox.translate_in("/a/b/c/file.txt"),
+            Some(args[2].clone())
+        );
+        assert_eq!(pathbox.translate_in("/x.txt"), Some(args[3].clone()));
 
-        let p = do_process("./foo:./bar").unwrap();
+        // Paths within files, and prefixes which aren't whole components,
+        // aren't translated.
+        assert_eq!(pathbox.translate_in("/x.txt/y"), None);
+        assert_eq!(pathbox.translate_in("/ab"), None);
+        assert_eq!(pathbox.translate_in("/"), None);
+    }
+
+    #[test]
+    fn test_hide_extensions() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox.set_hide_extensions(true);
+        let args = pathbox
+            .process_args(
+                ["/foo/bar.csv", "baz.tar.gz"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+        for arg in &args {
+            assert_eq!(arg.len(), "guest-path.".len() + 36);
+            assert!(!arg[11..].contains('.'));
+        }
+        assert_eq!(
+            pathbox.host_path(&args[0], Access::Read).unwrap(),
+            PathBuf::from("/foo/bar.csv")
+        );
+        assert_eq!(
+            pathbox.host_path(&args[1], Access::Read).unwrap(),
+            PathBuf::from("baz.tar.gz")
+        );
+
+        #[cfg(unix)]
+        {
+            let arg = pathbox
+                .process_arg_os(OsString::from_vec(b"/\xff.csv".to_vec()))
+                .unwrap();
+            assert!(!arg.ends_with(".csv"));
+        }
+    }
+
+    #[test]
+    fn test_path_mapping() {
+        let p = do_process("/a=/b").unwrap();
         assert_eq!(p.grants.len(), 2);
+        assert_eq!(p.grants[0].original, "/a");
+        assert_eq!(p.grants[1].original, "/b");
         assert_eq!(
             p.arg,
-            format!("{}:{}", p.grants[0].guest, p.grants[1].guest)
+            format!("{}={}", p.grants[0].guest, p.grants[1].guest)
         );
-        assert_eq!(p.grants[0].original, "./foo");
-        assert_eq!(p.grants[1].original, "./bar");
+
+        let p = do_process("--flag=/b").unwrap();
+        assert_eq!(p.grants.len(), 1);
+        assert_eq!(p.grants[0].original, "/b");
+        assert_eq!(p.arg, format!("--flag={}", p.grants[0].guest));
+
+        assert_eq!(do_process("a=b"), Ok(Process::new("a=b", &[])));
+
+        // Hive-style partitions are a single path.
+        let p = do_process("/data/year=2024/part.parquet").unwrap();
+        assert_eq!(p.grants.len(), 1);
+        assert_eq!(p.grants[0].original, "/data/year=2024/part.parquet");
+        assert_eq!(p.arg, p.grants[0].guest);
+    }
+
+    #[test]
+    fn test_merge() {
+        // Import variants individually, as `Access::None` would shadow
+        // `Option::None`.
+        use Access::{Any, Append, MutableDir, Read, ReadExec, ReadWrite, ReadonlyDir, Write};
+
+        let all = [
+            Read,
+            Write,
+            Append,
+            ReadWrite,
+            ReadExec,
+            ReadonlyDir,
+            MutableDir,
+            Any,
+            Access::None,
+        ];
+        for a in all {
+            assert_eq!(a.merge(a), Some(a));
+            assert_eq!(a.merge(Any), Some(Any));
+            for b in all {
+                let merged = a.merge(b);
+                assert_eq!(merged, b.merge(a));
+                if let Some(merged) = merged {
+                    assert!(merged >= a && merged >= b);
+
+                    // Merging never adds access which neither side had.
+                    for c in all {
+                        if merged.includes(c) {
+                            assert!(a.includes(c) || b.includes(c) || c == ReadWrite);
+                        }
+                    }
+                    if merged.includes(Write) {
+                        assert!(a.includes(Write) || b.includes(Write));
+                    }
+                }
+            }
+        }
+        assert_eq!(Read.merge(Write), Some(ReadWrite));
+        assert_eq!(Read.merge(Append), None);
+        assert_eq!(Append.merge(Write), Some(Write));
+        assert_eq!(ReadonlyDir.merge(MutableDir), Some(MutableDir));
+        assert_eq!(Read.merge(ReadonlyDir), None);
+        assert_eq!(Read.merge(ReadExec), Some(ReadExec));
+        assert_eq!(Write.merge(ReadExec), None);
+        assert_eq!(Access::None.merge(Read), Some(Read));
+        assert!(Access::None < ReadonlyDir);
+        assert_eq!(Read.partial_cmp(&Write), None);
+
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let args = pathbox
+            .process_args(["%read:/foo".to_owned(), "%write:/foo".to_owned()].into_iter())
+            .unwrap();
+        assert_eq!(args[0], args[1]);
+        assert_eq!(pathbox.as_slice().len(), 1);
+        assert_eq!(pathbox.as_slice()[0].access, ReadWrite);
+
+        // A file access and a directory access aren't merged into one which
+        // permits writing, even when escapes are limited to readonly.
+        let mut pathbox = Pathbox::new(MagicLevel::Readonly);
+        pathbox.set_limit_escapes(true);
+        let arg = pathbox
+            .process_arg("%read:/etc/foo.txt".to_owned())
+            .unwrap();
+        pathbox
+            .process_arg("%dir:/etc/foo.txt".to_owned())
+            .unwrap_err();
+        pathbox.upgrade(&arg, ReadonlyDir).unwrap_err();
+        assert_eq!(pathbox.as_slice()[0].access, Read);
         assert_eq!(
-            do_process("%verbatim:./foo:./bar"),
-            Ok(Process::new("./foo:./bar", &[]))
+            pathbox.host_path(&arg, Write).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
         );
     }
 
     #[test]
-    fn test_more_colon_separated() {
-        let name = "name:with:colons";
-        assert_eq!(do_process(name), Ok(Process::new("name:with:colons", &[])));
+    fn test_env_value() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(
+                [
+                    "%read:/foo/in put.txt".to_owned(),
+                    "%write:100%.txt".to_owned(),
+                    "%mutable-dir:/bar\nbaz".to_owned(),
+                    "%read-range:10-20:/foo/in put.txt".to_owned(),
+                    "%read:-".to_owned(),
+                    "qux/".to_owned(),
+                ]
+                .into_iter(),
+            )
+            .unwrap();
 
-        let name = "/name:%with/:col/ons";
+        let var_name = "PATHBOX_TEST_ENV_VALUE";
+        std::env::set_var(var_name, pathbox.to_env_value());
+        let child = Pathbox::from_env(var_name, MagicLevel::None).unwrap();
+        assert_eq!(child.as_slice(), pathbox.as_slice());
+        assert_eq!(child.as_slice().len(), args.len());
+
+        for bad in [
+            "read",
+            "read guest-path.x",
+            "sing guest-path.x /foo",
+            "read /foo /foo",
+            "read guest-path.x /foo%2",
+            "read:10 guest-path.x /foo",
+            "read:20-10 guest-path.x /foo",
+        ] {
+            std::env::set_var(var_name, bad);
+            assert!(Pathbox::from_env(var_name, MagicLevel::None).is_err());
+        }
+        std::env::remove_var(var_name);
+    }
+
+    #[test]
+    fn test_cwd() {
+        let cwd = std::env::current_dir().unwrap();
+
+        let p = do_process("%cwd").unwrap();
+        assert!(p.arg.starts_with("guest-path."));
+        assert_eq!(p.grants.len(), 1);
+        assert_eq!(p.grants[0].original, cwd.as_os_str());
+        assert_eq!(p.grants[0].access, Access::ReadonlyDir);
+
+        let p = do_process("%mutable-cwd").unwrap();
+        assert_eq!(p.grants[0].original, cwd.as_os_str());
+        assert_eq!(p.grants[0].access, Access::MutableDir);
+
+        do_process("%cwd:").unwrap_err();
+        assert_eq!(do_process("%verbatim:%cwd").unwrap().arg, "%cwd");
+    }
+
+    #[test]
+    fn test_temp_dir() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let args = pathbox
+            .process_args(["%temp-dir:", "%temp-dir:"].into_iter().map(str::to_owned))
+            .unwrap();
+        assert_ne!(args[0], args[1]);
+        let paths: Vec<_> = pathbox
+            .grants()
+            .map(|g| PathBuf::from(&g.original))
+            .collect();
+        assert!(pathbox.grants().all(|g| g.access == Access::MutableDir));
+        assert!(paths.iter().all(|path| path.is_dir()));
+
+        pathbox
+            .open_mutable_dir(&args[0])
+            .unwrap()
+            .write("file.txt", b"data")
+            .unwrap();
+        assert_eq!(std::fs::read(paths[0].join("file.txt")).unwrap(), b"data");
+
+        // The directories are removed when the `Pathbox` is dropped.
+        drop(pathbox);
+        assert!(paths.iter().all(|path| !path.exists()));
+
+        // Unless the grants are taken.
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        pathbox.process_arg("%temp-dir:".to_owned()).unwrap();
+        let path = PathBuf::from(&pathbox.as_slice()[0].original);
+        let grants = pathbox.into_grants();
+        assert!(path.is_dir());
+        assert_eq!(grants.len(), 1);
+        std::fs::remove_dir(&path).unwrap();
+
+        // The directory can't be placed elsewhere from the command line.
+        let parent = tempfile::tempdir().unwrap();
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        pathbox
+            .process_arg(format!("%temp-dir:{}", parent.path().display()))
+            .unwrap_err();
+        assert!(pathbox.is_empty());
+        assert_eq!(std::fs::read_dir(parent.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_escape_handler() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        pathbox.set_escape_handler(|escape| {
+            if let Some(name) = escape.strip_prefix("secret:") {
+                if name.is_empty() {
+                    return Err(Error::new("empty secret name"));
+                }
+                Ok(EscapeResult::Verbatim(format!("<{}>", name)))
+            } else if let Some(path) = escape.strip_prefix("log:") {
+                Ok(EscapeResult::Grant(path.into(), Access::Append))
+            } else if escape.starts_with("read:") {
+                Ok(EscapeResult::Verbatim("shadowed".to_owned()))
+            } else {
+                Ok(EscapeResult::Unhandled)
+            }
+        });
+
+        let args = pathbox
+            .process_args_annotated(
+                ["%secret:key", "%log:/var/log/x.log", "%read:/a.txt"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+        assert_eq!(args[0], ("<key>".to_owned(), None));
+        assert_eq!(args[1].1, Some(Access::Append));
         assert_eq!(
-            do_process(name),
-            Ok(Process::new("/name:%with/:col/ons", &[]))
+            pathbox.host_path(&args[1].0, Access::Append).unwrap(),
+            Path::new("/var/log/x.log")
         );
 
-        let name = "/name:with/:col/ons";
-        let p = do_process(name).unwrap();
+        // Built-in escapes take precedence.
+        assert_eq!(args[2].1, Some(Access::Read));
+
+        pathbox.process_arg("%secret:".to_owned()).unwrap_err();
+        pathbox.process_arg("%other:x".to_owned()).unwrap_err();
+
+        // Requested grants are checked like built-in ones.
+        let mut pathbox = Pathbox::new(MagicLevel::Readonly);
+        pathbox.set_limit_escapes(true);
+        pathbox.set_escape_handler(|_| Ok(EscapeResult::Grant("/x".into(), Access::Write)));
+        pathbox.process_arg("%custom".to_owned()).unwrap_err();
+        assert!(pathbox.is_empty());
+    }
+
+    #[test]
+    fn test_read_range() {
+        let p = do_process("%read-range:3-7:/foo.txt").unwrap();
+        assert_eq!(p.grants.len(), 1);
+        assert_eq!(p.grants[0].original, "/foo.txt");
+        assert_eq!(p.grants[0].access, Access::Read);
+        assert_eq!(p.grants[0].range, Some(3..7));
+        assert!(p.arg.ends_with(".txt"));
+
+        // An empty range is fine.
+        let p = do_process("%read-range:5-5:/foo").unwrap();
+        assert_eq!(p.grants[0].range, Some(5..5));
+
+        for bad in [
+            "%read-range:/foo",
+            "%read-range:3:/foo",
+            "%read-range:3-:/foo",
+            "%read-range:-7:/foo",
+            "%read-range:7-3:/foo",
+            "%read-range:x-7:/foo",
+        ] {
+            do_process(bad).unwrap_err();
+        }
+
+        // Ranged and unranged grants of the same file are kept separate.
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(["%read-range:0-1:/foo".to_owned(), "%read:/foo".to_owned()].into_iter())
+            .unwrap();
+        assert_ne!(args[0], args[1]);
+        assert_eq!(pathbox.as_slice().len(), 2);
         assert_eq!(
-            p.arg,
-            format!(
-                "{}:{}:{}",
-                p.grants[0].guest, p.grants[1].guest, p.grants[2].guest
-            )
+            pathbox
+                .host_path(&args[0], Access::Read)
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            pathbox.host_path(&args[1], Access::Read).unwrap(),
+            PathBuf::from("/foo")
         );
     }
 
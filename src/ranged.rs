@@ -0,0 +1,77 @@
+use cap_std::fs::File;
+use dir_view::cap_std;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+/// A file opened with [`Pathbox::open_range`], which only permits reading a
+/// range of its bytes.
+///
+/// Offsets are relative to the start of the file, rather than the start of
+/// the range, and seeking relative to the end seeks relative to the end of
+/// the range.
+///
+/// [`Pathbox::open_range`]: crate::Pathbox::open_range
+#[derive(Debug)]
+pub struct RangedFile {
+    file: File,
+    range: Range<u64>,
+    pos: u64,
+}
+
+impl RangedFile {
+    pub(crate) fn new(mut file: File, range: Range<u64>) -> io::Result<Self> {
+        file.seek(SeekFrom::Start(range.start))?;
+        Ok(Self {
+            pos: range.start,
+            file,
+            range,
+        })
+    }
+
+    /// Return the range of bytes which may be read.
+    pub fn range(&self) -> Range<u64> {
+        self.range.clone()
+    }
+}
+
+impl Read for RangedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.range.end {
+            return Ok(0);
+        }
+        if !self.range.contains(&self.pos) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Offset {} is outside of the permitted range {}..{}",
+                    self.pos, self.range.start, self.range.end
+                ),
+            ));
+        }
+        let avail = self.range.end - self.pos;
+        let len = buf.len().min(usize::try_from(avail).unwrap_or(usize::MAX));
+        let n = self.file.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for RangedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => (offset, 0),
+            SeekFrom::End(offset) => (self.range.end, offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        let pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+        // Seeking outside of the range is permitted, as with ordinary files,
+        // but reading from there isn't.
+        self.pos = self.file.seek(SeekFrom::Start(pos))?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,97 @@
+use crate::Pathbox;
+use std::ffi::OsStr;
+use std::io;
+use std::process::Command;
+#[cfg(unix)]
+use {
+    crate::pathbox::Access,
+    dir_view::{ambient_authority, cap_std},
+    std::os::unix::io::{AsRawFd, OwnedFd},
+    std::os::unix::process::CommandExt,
+};
+
+/// The name of the environment variable which tells a child process which
+/// file descriptors correspond to which guest paths.
+#[cfg(unix)]
+const FDS_VAR: &str = "PATHBOX_FDS";
+
+pub(crate) fn command(
+    pathbox: &Pathbox,
+    program: &OsStr,
+    args: &[String],
+    vars: &[(String, String)],
+) -> io::Result<Command> {
+    let mut command = Command::new(program);
+    command.args(args).env_clear().envs(vars.iter().cloned());
+
+    #[cfg(unix)]
+    {
+        let mut fds = Vec::new();
+        let mut list = Vec::new();
+        for grant in pathbox.as_slice() {
+            // A file descriptor would give the child access to the whole
+            // file, so byte-range grants aren't passed. Standard input is
+            // inherited, so it isn't passed either.
+            if grant.range.is_some() || grant.stdin {
+                continue;
+            }
+            let fd = match open_grant(&grant.original, grant.access)? {
+                Some(fd) => fd,
+                None => continue,
+            };
+            list.push(format!("{}={}", grant.guest, fd.as_raw_fd()));
+            fds.push(fd);
+        }
+        command.env(FDS_VAR, list.join(":"));
+
+        // SAFETY: `fcntl` is async-signal-safe, and the closure doesn't
+        // allocate.
+        unsafe {
+            command.pre_exec(move || {
+                for fd in &fds {
+                    rustix::io::fcntl_setfd(fd, rustix::io::FdFlags::empty())?;
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = pathbox;
+
+    Ok(command)
+}
+
+/// Open the file or directory for a grant so that it can be passed to a
+/// child process, or return `None` if the grant isn't passed.
+#[cfg(unix)]
+fn open_grant(original: &OsStr, access: Access) -> io::Result<Option<OwnedFd>> {
+    let mut options = cap_std::fs::OpenOptions::new();
+    // Don't create or truncate files here; the child may not open them.
+    match access {
+        Access::Read | Access::ReadExec => options.read(true),
+        Access::Write => options.write(true),
+        Access::Append => options.append(true),
+        Access::ReadWrite => options.read(true).write(true),
+        Access::ReadonlyDir | Access::MutableDir => {
+            let dir = cap_std::fs::Dir::open_ambient_dir(original, ambient_authority())?;
+            return Ok(Some(dir.into_std_file().into()));
+        }
+        // We don't know how the child will want to use it.
+        Access::Any => return Ok(None),
+        // The child may not open it at all.
+        Access::None => return Ok(None),
+    };
+    let file = match cap_std::fs::File::open_ambient_with(original, &options, ambient_authority()) {
+        Ok(file) => file,
+        // Files granted for writing which don't exist yet aren't passed.
+        Err(err)
+            if err.kind() == io::ErrorKind::NotFound
+                && matches!(access, Access::Write | Access::Append | Access::ReadWrite) =>
+        {
+            return Ok(None)
+        }
+        Err(err) => return Err(err),
+    };
+    Ok(Some(file.into_std().into()))
+}
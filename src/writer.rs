@@ -1,82 +1,577 @@
 //! TODO: The implementation here is extremely primitive and unoptimized.
 
 use crate::Pathbox;
+use std::fmt;
 use std::io;
 #[cfg(unix)]
 use std::os::unix::ffi::OsStrExt;
 
-pub(crate) fn stdout(pathbox: &Pathbox) -> Writer<'_> {
-    Writer::new(pathbox, Box::new(std::io::stdout()))
+/// The number of translated bytes to accumulate before writing them to the
+/// inner stream.
+pub(crate) const BUFFER_THRESHOLD: usize = 8 * 1024;
+
+pub(crate) fn stdout(pathbox: &Pathbox, inner: Option<Box<dyn io::Write>>) -> Writer<'_> {
+    let (inner, stream) = match inner {
+        Some(inner) => (inner, Stream::Other),
+        None => (
+            Box::new(std::io::stdout()) as Box<dyn io::Write>,
+            Stream::Stdout,
+        ),
+    };
+    Writer::new(pathbox, inner, stream, false)
+}
+
+pub(crate) fn stderr(pathbox: &Pathbox, inner: Option<Box<dyn io::Write>>) -> Writer<'_> {
+    let (inner, stream) = match inner {
+        Some(inner) => (inner, Stream::Other),
+        None => (
+            Box::new(std::io::stderr()) as Box<dyn io::Write>,
+            Stream::Stderr,
+        ),
+    };
+    // Errors should appear promptly, so flush stderr on every line.
+    Writer::new(pathbox, inner, stream, true)
 }
 
-pub(crate) fn stderr(pathbox: &Pathbox) -> Writer<'_> {
-    Writer::new(pathbox, Box::new(std::io::stderr()))
+/// Which stream a `Writer` writes to, for its `Debug` output.
+#[derive(Debug, Clone, Copy)]
+enum Stream {
+    Stdout,
+    Stderr,
+    /// A stream set with `Pathbox::set_stdout` or `Pathbox::set_stderr`.
+    Other,
 }
 
 /// A standard-output stream that's linked to a [`Pathbox`] and translates
 /// guest paths back into their external presentation.
+///
+/// Output is translated a line at a time, and buffered until
+/// [`flush`](io::Write::flush) is called, the `Writer` is dropped, or enough
+/// output accumulates. Very long lines are translated in pieces, so that
+/// output without newlines doesn't accumulate without bound.
+///
+/// Flushing also writes any partial line, except for a trailing internal
+/// filename which may be incomplete, which is held until more output
+/// completes it.
 pub struct Writer<'a> {
     pathbox: &'a Pathbox,
     inner: Box<dyn io::Write>,
     buf: Vec<u8>,
+    out: Vec<u8>,
+    stream: Stream,
+    line_flush: bool,
 }
 
 impl<'a> Writer<'a> {
-    fn new(pathbox: &'a Pathbox, inner: Box<dyn io::Write>) -> Self {
+    fn new(
+        pathbox: &'a Pathbox,
+        inner: Box<dyn io::Write>,
+        stream: Stream,
+        line_flush: bool,
+    ) -> Self {
         Self {
             pathbox,
             inner,
             buf: Vec::new(),
+            out: Vec::new(),
+            stream,
+            line_flush,
         }
     }
 
-    fn replace_guest_paths(&mut self) {
-        if let Some((before, _after_prefix)) = is_subsequence(b"guest-path.", &self.buf) {
-            for grant in self.pathbox.as_slice() {
-                let after_match = before + grant.guest.len();
-                if self.buf.get(before..after_match) == Some(grant.guest.as_bytes()) {
-                    let after = self.buf[after_match..].to_vec();
-                    self.buf.resize(before, 0);
+    /// Write all translated output accumulated so far to the inner stream.
+    ///
+    /// If the inner stream fails partway through, the bytes it did accept
+    /// are removed from the buffer, so that retrying doesn't write them
+    /// twice.
+    fn flush_out(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.out.len() {
+                break Ok(());
+            }
+            match self.inner.write(&self.out[written..]) {
+                Ok(0) => {
+                    break Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    ))
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        self.out.drain(..written);
+        result
+    }
+
+    fn should_flush(&self) -> bool {
+        !self.out.is_empty() && (self.line_flush || self.out.len() >= BUFFER_THRESHOLD)
+    }
+}
+
+/// Replace the guest paths in `buf` with their external presentations.
+pub(crate) fn replace_guest_paths(pathbox: &Pathbox, buf: &mut Vec<u8>) {
+    let mut start = 0;
+    while let Some((before, after_prefix)) = is_subsequence(b"guest-path.", &buf[start..]) {
+        let before = start + before;
+
+        // If several guest paths match, use the longest, as lookups do.
+        let grant = pathbox
+            .as_slice()
+            .iter()
+            .filter(|grant| buf[before..].starts_with(grant.guest.as_bytes()))
+            .max_by_key(|grant| grant.guest.len());
+        let Some(grant) = grant else {
+            start += after_prefix;
+            continue;
+        };
+
+        let after = buf[before + grant.guest.len()..].to_vec();
+        buf.resize(before, 0);
 
-                    #[cfg(unix)]
-                    self.buf.extend_from_slice(grant.original.as_bytes());
-                    #[cfg(not(unix))]
-                    self.buf
-                        .extend_from_slice(grant.original.as_os_str().to_str().unwrap().as_bytes());
+        #[cfg(unix)]
+        buf.extend_from_slice(grant.original.as_bytes());
+        #[cfg(not(unix))]
+        buf.extend_from_slice(grant.original.as_os_str().to_str().unwrap().as_bytes());
 
-                    self.buf.extend_from_slice(&after);
+        // Separators are ASCII, so they can be replaced bytewise.
+        if let Some(separator) = pathbox.output_separator() {
+            for byte in &mut buf[before..] {
+                if *byte == b'/' || *byte == b'\\' {
+                    *byte = separator as u8;
                 }
             }
         }
+
+        // Continue after the replacement, so that host paths which happen to
+        // contain `guest-path.` aren't translated again.
+        start = buf.len();
+        buf.extend_from_slice(&after);
+    }
+}
+
+/// Append the complete lines in `input` to `out`, translating guest paths,
+/// and stash any trailing partial line in `buf`.
+pub(crate) fn translate_lines(
+    pathbox: &Pathbox,
+    buf: &mut Vec<u8>,
+    out: &mut Vec<u8>,
+    input: &[u8],
+) {
+    let mut work = input;
+    while let Some(line) = work.iter().position(|b| *b == b'\n') {
+        buf.extend_from_slice(&work[..=line]);
+        replace_guest_paths(pathbox, buf);
+        out.append(buf);
+        work = &work[line + 1..];
+    }
+    buf.extend_from_slice(work);
+
+    // Translate very long lines in pieces, so that output without newlines
+    // doesn't accumulate without bound.
+    if buf.len() >= BUFFER_THRESHOLD {
+        translate_partial_line(pathbox, buf, out);
     }
 }
 
+/// Append the partial line in `buf` to `out`, translating guest paths,
+/// except for a tail which could be the start of an incomplete guest path.
+///
+/// Guest paths have a maximum length, so the tail is bounded.
+pub(crate) fn translate_partial_line(pathbox: &Pathbox, buf: &mut Vec<u8>, out: &mut Vec<u8>) {
+    const PREFIX: &[u8] = b"guest-path.";
+    let window = pathbox
+        .as_slice()
+        .iter()
+        .map(|grant| grant.guest.len())
+        .max()
+        .unwrap_or(0);
+
+    // A guest path starting before the last `window` bytes is complete, so
+    // only look for incomplete ones after that.
+    let lo = buf.len().saturating_sub(window);
+    let cut = (lo..buf.len())
+        .find(|&start| {
+            let tail = &buf[start..];
+            PREFIX.starts_with(tail)
+                || (tail.starts_with(PREFIX)
+                    && !pathbox
+                        .as_slice()
+                        .iter()
+                        .any(|grant| tail.starts_with(grant.guest.as_bytes())))
+        })
+        .unwrap_or(buf.len());
+
+    let mut head: Vec<u8> = buf.drain(..cut).collect();
+    replace_guest_paths(pathbox, &mut head);
+    out.append(&mut head);
+}
+
+/// Append all of `buf` to `out`, translating guest paths, when no more
+/// output is coming, so that a trailing partial line isn't lost.
+pub(crate) fn translate_rest(pathbox: &Pathbox, buf: &mut Vec<u8>, out: &mut Vec<u8>) {
+    replace_guest_paths(pathbox, buf);
+    out.append(buf);
+}
+
+/// Find the first occurrence of `needle` in `haystack`, and return its start
+/// and end offsets.
 fn is_subsequence(needle: &[u8], haystack: &[u8]) -> Option<(usize, usize)> {
-    if needle.len() <= haystack.len() {
-        for i in 0..haystack.len() - needle.len() {
-            if &haystack[i..i + needle.len()] == needle {
-                return Some((i, i + needle.len()));
-            }
-        }
+    // An empty needle occurs at the start of any haystack.
+    if needle.is_empty() {
+        return Some((0, 0));
     }
-    None
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|i| (i, i + needle.len()))
 }
 
 impl<'a> io::Write for Writer<'a> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let mut work = buf;
-        while let Some(line) = work.iter().position(|b| *b == b'\n') {
-            self.buf.extend_from_slice(&work[..=line]);
-            self.replace_guest_paths();
-            self.inner.write_all(&self.buf)?;
-            work = &work[line + 1..];
-            self.buf.clear();
+        // If output from a previous call is still pending, write it out
+        // before accepting any more. If that fails, report the error without
+        // having consumed any of `buf`.
+        if self.should_flush() {
+            self.flush_out()?;
+        }
+
+        translate_lines(self.pathbox, &mut self.buf, &mut self.out, buf);
+
+        // All of `buf` is now held in our buffers, so report it as written.
+        // If the inner stream fails here, the remaining output stays
+        // buffered, and the error is reported by the next `write` or `flush`.
+        if self.should_flush() {
+            let _ = self.flush_out();
         }
-        self.buf.extend_from_slice(work);
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        // Write out any partial line too, so that output like progress
+        // indicators appears promptly.
+        translate_partial_line(self.pathbox, &mut self.buf, &mut self.out);
+        self.flush_out()?;
         self.inner.flush()
     }
 }
+
+impl<'a> fmt::Debug for Writer<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The inner stream isn't required to implement `Debug`, so just
+        // describe which stream it is.
+        f.debug_struct("Writer")
+            .field("stream", &self.stream)
+            .field("buffered", &(self.buf.len() + self.out.len()))
+            .finish_non_exhaustive()
+    }
+}
+
+impl<'a> Drop for Writer<'a> {
+    fn drop(&mut self) {
+        // Like `BufWriter`, ignore errors here; users wanting to handle them
+        // should call `flush` explicitly. No more output is coming, so write
+        // out any partial line too, so that it isn't lost.
+        translate_rest(self.pathbox, &mut self.buf, &mut self.out);
+        let _ = self.flush_out();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Level, MagicLevel};
+    use std::cell::{Cell, RefCell};
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    /// A stream which appends to a shared buffer.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A stream which counts how many times it's flushed.
+    struct CountFlushes(Arc<Mutex<usize>>);
+
+    impl io::Write for CountFlushes {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            *self.0.lock().unwrap() += 1;
+            Ok(())
+        }
+    }
+
+    /// A mock stream which accepts bytes up to a limit and then fails.
+    struct FailAfter {
+        written: Rc<RefCell<Vec<u8>>>,
+        limit: Rc<Cell<usize>>,
+    }
+
+    impl io::Write for FailAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut written = self.written.borrow_mut();
+            let avail = self.limit.get().saturating_sub(written.len());
+            if avail == 0 {
+                return Err(io::Error::other("mock failure"));
+            }
+            let n = buf.len().min(avail);
+            written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_is_subsequence() {
+        assert_eq!(is_subsequence(b"", b""), Some((0, 0)));
+        assert_eq!(is_subsequence(b"", b"abc"), Some((0, 0)));
+        assert_eq!(is_subsequence(b"abc", b"abc"), Some((0, 3)));
+        assert_eq!(is_subsequence(b"abc", b"xabc"), Some((1, 4)));
+        assert_eq!(is_subsequence(b"abc", b"abcabc"), Some((0, 3)));
+        assert_eq!(is_subsequence(b"bc", b"abc"), Some((1, 3)));
+        assert_eq!(is_subsequence(b"abd", b"abc"), None);
+        assert_eq!(is_subsequence(b"abcd", b"abc"), None);
+        assert_eq!(is_subsequence(b"a", b""), None);
+    }
+
+    #[test]
+    fn test_translate() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(["/foo/bar.txt".to_owned(), "baz/".to_owned()].into_iter())
+            .unwrap();
+        let stdout = Capture::default();
+        let stderr = Capture::default();
+        let (out, err) = (stdout.clone(), stderr.clone());
+        pathbox.set_stdout(move || Box::new(out.clone()));
+        pathbox.set_stderr(move || Box::new(err.clone()));
+
+        writeln!(pathbox.stdout(), "reading {}", args[0]).unwrap();
+        write!(pathbox.stdout(), "{}\nunfinished", args[1]).unwrap();
+        writeln!(pathbox.stderr(), "{}: not found", args[0]).unwrap();
+
+        assert_eq!(
+            *stdout.0.lock().unwrap(),
+            b"reading /foo/bar.txt\nbaz/\nunfinished"
+        );
+        assert_eq!(*stderr.0.lock().unwrap(), b"/foo/bar.txt: not found\n");
+    }
+
+    #[test]
+    fn test_output_separator() {
+        let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+        let args = pathbox
+            .process_args(["%read:/foo/bar.txt".to_owned(), "%dir:a\\b".to_owned()].into_iter())
+            .unwrap();
+        let stdout = Capture::default();
+        let out = stdout.clone();
+        pathbox.set_stdout(move || Box::new(out.clone()));
+
+        // By default, paths are emitted verbatim.
+        writeln!(pathbox.stdout(), "{}\n{}/c", args[0], args[1]).unwrap();
+        pathbox.set_output_separator(Some('\\'));
+        writeln!(pathbox.stdout(), "{}\n{}/c", args[0], args[1]).unwrap();
+        pathbox.set_output_separator(Some('/'));
+        writeln!(pathbox.stdout(), "{}\n{}/c", args[0], args[1]).unwrap();
+
+        // Only the substituted paths are normalized.
+        assert_eq!(
+            *stdout.0.lock().unwrap(),
+            b"/foo/bar.txt\na\\b/c\n\\foo\\bar.txt\na\\b/c\n/foo/bar.txt\na/b/c\n"
+        );
+    }
+
+    #[test]
+    fn test_log_grants() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        pathbox
+            .process_args(
+                ["/foo/bar.txt", "%dir:/baz", "%read-range:1-2:/qux"]
+                    .into_iter()
+                    .map(str::to_owned),
+            )
+            .unwrap();
+        let stderr = Capture::default();
+        let err = stderr.clone();
+        pathbox.set_stderr(move || Box::new(err.clone()));
+
+        pathbox.log_grants(Level::Info);
+        assert_eq!(
+            String::from_utf8(stderr.0.lock().unwrap().clone()).unwrap(),
+            "[INFO pathbox] granted any access to /foo/bar.txt\n\
+             [INFO pathbox] granted dir access to /baz\n\
+             [INFO pathbox] granted read access to bytes 1..2 of /qux\n"
+        );
+    }
+
+    #[test]
+    fn test_long_line() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(["/foo/bar.txt".to_owned()].into_iter())
+            .unwrap();
+        let stdout = Capture::default();
+        let out = stdout.clone();
+        pathbox.set_stdout(move || Box::new(out.clone()));
+
+        // Write a line with no newline, with a guest path straddling the
+        // point where it's split.
+        let mut stdout_writer = pathbox.stdout();
+        let filler = vec![b'x'; BUFFER_THRESHOLD - 10];
+        stdout_writer.write_all(&filler).unwrap();
+        write!(stdout_writer, " {} ", args[0]).unwrap();
+        for _ in 0..100 {
+            stdout_writer.write_all(&filler).unwrap();
+        }
+        stdout_writer.flush().unwrap();
+
+        // Nearly everything has been written, without waiting for a newline.
+        assert!(stdout_writer.buf.len() < 2 * args[0].len());
+        let written = stdout.0.lock().unwrap().clone();
+        assert!(written.len() > 100 * filler.len());
+        let expected = [&filler[..], b" /foo/bar.txt "].concat();
+        assert!(written.starts_with(&expected));
+        assert!(written[expected.len()..].iter().all(|b| *b == b'x'));
+    }
+
+    #[test]
+    fn test_several_paths() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(["/foo/bar.txt".to_owned(), "baz/".to_owned()].into_iter())
+            .unwrap();
+        let stdout = Capture::default();
+        let out = stdout.clone();
+        pathbox.set_stdout(move || Box::new(out.clone()));
+
+        let mut stdout_writer = pathbox.stdout();
+        writeln!(stdout_writer, "{} -> {} {}", args[0], args[1], args[0]).unwrap();
+
+        // Several guest paths in a long line which is translated in pieces.
+        let filler = vec![b'x'; BUFFER_THRESHOLD];
+        let mut line = filler.clone();
+        line.extend_from_slice(format!(" {} {} ", args[0], args[1]).as_bytes());
+        line.extend_from_slice(&filler);
+        stdout_writer.write_all(&line).unwrap();
+        stdout_writer.flush().unwrap();
+
+        let expected = [
+            &b"/foo/bar.txt -> baz/ /foo/bar.txt\n"[..],
+            &filler,
+            b" /foo/bar.txt baz/ ",
+            &filler,
+        ]
+        .concat();
+        assert_eq!(*stdout.0.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_flush_partial_line() {
+        let mut pathbox = Pathbox::new(MagicLevel::Auto);
+        let args = pathbox
+            .process_args(["/foo/bar.txt".to_owned()].into_iter())
+            .unwrap();
+        let stdout = Capture::default();
+        let out = stdout.clone();
+        pathbox.set_stdout(move || Box::new(out.clone()));
+        let written = || String::from_utf8(stdout.0.lock().unwrap().clone()).unwrap();
+
+        let mut writer = pathbox.stdout();
+        write!(writer, "progress: 50%").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(written(), "progress: 50%");
+
+        // A complete guest path is translated.
+        write!(writer, " {}", args[0]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(written(), "progress: 50% /foo/bar.txt");
+
+        // An incomplete one is held until it's complete.
+        let (start, end) = args[0].split_at(20);
+        write!(writer, " {}", start).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(written(), "progress: 50% /foo/bar.txt ");
+        write!(writer, "{} done", end).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(written(), "progress: 50% /foo/bar.txt /foo/bar.txt done");
+    }
+
+    #[test]
+    fn test_flush_streams() {
+        let mut pathbox = Pathbox::new(MagicLevel::None);
+        let flushes = Arc::new(Mutex::new(0));
+        let count = flushes.clone();
+        pathbox.set_stdout(move || Box::new(CountFlushes(count.clone())) as Box<dyn io::Write>);
+        let count = flushes.clone();
+        pathbox.set_stderr(move || Box::new(CountFlushes(count.clone())) as Box<dyn io::Write>);
+
+        pathbox.flush_streams().unwrap();
+        assert_eq!(*flushes.lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut pathbox = Pathbox::new(MagicLevel::None);
+        assert_eq!(
+            format!("{:?}", pathbox.stdout()),
+            "Writer { stream: Stdout, buffered: 0, .. }"
+        );
+        assert_eq!(
+            format!("{:?}", pathbox.stderr()),
+            "Writer { stream: Stderr, buffered: 0, .. }"
+        );
+
+        pathbox.set_stdout(|| Box::new(Capture::default()));
+        let mut stdout = pathbox.stdout();
+        write!(stdout, "partial").unwrap();
+        assert_eq!(
+            format!("{:?}", stdout),
+            "Writer { stream: Other, buffered: 7, .. }"
+        );
+    }
+
+    #[test]
+    fn test_inner_failure() {
+        let pathbox = Pathbox::new(MagicLevel::None);
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let limit = Rc::new(Cell::new(3));
+        let inner = FailAfter {
+            written: written.clone(),
+            limit: limit.clone(),
+        };
+        let mut writer = Writer::new(&pathbox, Box::new(inner), Stream::Other, true);
+
+        // The inner stream fails after "hel", but the whole line is buffered.
+        assert_eq!(writer.write(b"hello\n").unwrap(), 6);
+        assert_eq!(*written.borrow(), b"hel");
+
+        // The pending output can't be written, so nothing new is consumed.
+        assert!(writer.write(b"world\n").is_err());
+        assert!(writer.flush().is_err());
+
+        // Once the inner stream recovers, everything is written exactly once.
+        limit.set(usize::MAX);
+        writer.write_all(b"world\n").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(*written.borrow(), b"hello\nworld\n");
+    }
+}
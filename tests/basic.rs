@@ -1,7 +1,9 @@
-use pathbox::{MagicLevel, Pathbox};
+#![cfg(feature = "std")]
+
+use pathbox::{Access, MagicLevel, Pathbox};
 use std::ffi::OsString;
 use std::fs;
-use std::io::{self, Read, Write};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
 #[test]
 fn copy() {
@@ -62,14 +64,14 @@ fn copy_fail_no_magic() {
     assert!(input_name.contains(real_input_name.to_str().unwrap()));
     assert!(output_name.contains(real_output_name.to_str().unwrap()));
 
-    // Everything fails.
+    // Everything fails, since nothing was granted.
     assert_eq!(
         pathbox.open(input_name).unwrap_err().kind(),
-        io::ErrorKind::PermissionDenied
+        io::ErrorKind::NotFound
     );
     assert_eq!(
         pathbox.create(output_name).unwrap_err().kind(),
-        io::ErrorKind::PermissionDenied
+        io::ErrorKind::NotFound
     );
 }
 
@@ -104,3 +106,1072 @@ fn copy_fail_no_writeability() {
         io::ErrorKind::PermissionDenied
     );
 }
+
+#[test]
+fn cap_dir() {
+    let readonly_dir = tempfile::tempdir().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    let args = [
+        format!("%dir:{}", readonly_dir.path().display()),
+        format!("%mutable-dir:{}", dir.path().display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // A readonly directory can't be opened as a `Dir`.
+    assert_eq!(
+        pathbox.open_cap_dir(&args[0]).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+
+    let cap_dir = pathbox.open_cap_dir(&args[1]).unwrap();
+    cap_dir.write("file.txt", b"some data\n").unwrap();
+    assert_eq!(
+        fs::read(dir.path().join("file.txt")).unwrap(),
+        b"some data\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn spawn() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+
+    let args = [format!("%read:{}", real_input_name.display())];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // The child reads the input from the file descriptor it inherited.
+    let script = "fd=${PATHBOX_FDS#*=}; eval \"cat <&$fd\"".to_owned();
+    let args = ["-c".to_owned(), script, args[0].clone()];
+    let output = pathbox.command("sh", &args, &[]).unwrap().output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"some data\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn spawn_does_not_create_outputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_output_name = dir.path().join("output.txt");
+    let real_existing_name = dir.path().join("existing.txt");
+    fs::write(&real_existing_name, b"keep\n").unwrap();
+
+    let args = [
+        format!("%write:{}", real_output_name.display()),
+        format!("%rwc:{}", real_existing_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // Building the command doesn't create or truncate anything, and only
+    // files which exist are passed.
+    let command = pathbox.command("true", &[], &[]).unwrap();
+    assert!(!real_output_name.exists());
+    assert_eq!(fs::read(&real_existing_name).unwrap(), b"keep\n");
+    let fds = command
+        .get_envs()
+        .find(|(name, _)| *name == "PATHBOX_FDS")
+        .and_then(|(_, value)| value)
+        .unwrap()
+        .to_str()
+        .unwrap();
+    assert!(!fds.contains(&args[0]), "{}", fds);
+    assert!(fds.contains(&args[1]), "{}", fds);
+}
+
+#[test]
+fn exists() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    let real_output_name = dir.path().join("output.txt");
+    let real_missing_name = dir.path().join("missing.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+    fs::write(&real_output_name, b"some data\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_input_name.display()),
+        format!("%write:{}", real_output_name.display()),
+        format!("%read:{}", real_missing_name.display()),
+        format!("%dir:{}", dir.path().display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    assert!(pathbox.exists(&args[0]));
+    // Without read access, existence isn't revealed.
+    assert!(!pathbox.exists(&args[1]));
+    assert!(!pathbox.exists(&args[2]));
+    assert!(pathbox.exists(&args[3]));
+    assert!(!pathbox.exists("input.txt"));
+}
+
+#[test]
+fn truncate() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    let real_output_name = dir.path().join("output.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+    fs::write(&real_output_name, b"some data\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_input_name.display()),
+        format!("%write:{}", real_output_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    assert_eq!(
+        pathbox.truncate(&args[0], 4).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    pathbox.truncate(&args[1], 4).unwrap();
+    assert_eq!(fs::read(&real_input_name).unwrap(), b"some data\n");
+    assert_eq!(fs::read(&real_output_name).unwrap(), b"some");
+}
+
+#[test]
+fn open_at_offset() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_input_name.display()),
+        format!("%append:{}", dir.path().join("log.txt").display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    let mut contents = String::new();
+    pathbox
+        .open_at_offset(&args[0], 5)
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "data\n");
+
+    assert_eq!(
+        pathbox.open_at_offset(&args[1], 0).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn open_range() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    fs::write(&real_input_name, b"0123456789").unwrap();
+
+    let args = [
+        format!("%read-range:2-6:{}", real_input_name.display()),
+        format!("%read:{}", real_input_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // The file starts out at the start of the range, and reads stop at the
+    // end of it.
+    let mut file = pathbox.open_range(&args[0]).unwrap();
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "2345");
+
+    // Reads starting exactly at the boundaries.
+    let mut buf = [0; 4];
+    assert_eq!(file.seek(SeekFrom::Start(5)).unwrap(), 5);
+    assert_eq!(file.read(&mut buf).unwrap(), 1);
+    assert_eq!(buf[0], b'5');
+    assert_eq!(file.read(&mut buf).unwrap(), 0);
+    assert_eq!(file.seek(SeekFrom::End(-4)).unwrap(), 2);
+    assert_eq!(file.read(&mut buf).unwrap(), 4);
+    assert_eq!(&buf, b"2345");
+
+    // Reads outside of the range fail.
+    for pos in [0, 1, 7, 10] {
+        file.seek(SeekFrom::Start(pos)).unwrap();
+        assert_eq!(
+            file.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    // Ranged grants can't be opened without the restriction.
+    assert_eq!(
+        pathbox.open(&args[0]).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+
+    // Unranged grants can be opened with `open_range` too.
+    let mut file = pathbox.open_range(&args[1]).unwrap();
+    assert_eq!(file.range(), 0..10);
+    contents.clear();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "0123456789");
+}
+
+#[test]
+fn open_temp() {
+    let dir = tempfile::tempdir().unwrap();
+    let readonly = tempfile::tempdir().unwrap();
+
+    let args = [
+        format!("%mutable-dir:{}", dir.path().display()),
+        format!("%dir:{}", readonly.path().display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    let (token, mut file) = pathbox.open_temp(&args[0]).unwrap();
+    assert!(token.starts_with(&format!("{}/", args[0])));
+    file.write_all(b"scratch").unwrap();
+
+    // The file is inside the granted directory.
+    let name = token.rsplit('/').next().unwrap();
+    assert_eq!(fs::read(dir.path().join(name)).unwrap(), b"scratch");
+    assert_eq!(
+        pathbox.open_dir(&args[0]).unwrap().read(name).unwrap(),
+        b"scratch"
+    );
+
+    // Each call creates a distinct file.
+    let (other, _file) = pathbox.open_temp(&args[0]).unwrap();
+    assert_ne!(token, other);
+
+    // Readonly directories can't be used.
+    assert_eq!(
+        pathbox.open_temp(&args[1]).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn append_line() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    let real_log_name = dir.path().join("log.txt");
+    fs::write(&real_log_name, b"start\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_input_name.display()),
+        format!("%append:{}", real_log_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    pathbox
+        .append_line(&args[1], &format!("read {}", args[0]))
+        .unwrap();
+    assert_eq!(
+        fs::read_to_string(&real_log_name).unwrap(),
+        format!("start\nread {}\n", real_input_name.display())
+    );
+
+    assert_eq!(
+        pathbox.append_line(&args[0], "nope").unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn reopen() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_input_name.display()),
+        format!("%dir:{}", dir.path().display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // Each handle is independent.
+    let mut first = pathbox.open(&args[0]).unwrap();
+    let mut contents = String::new();
+    first.read_to_string(&mut contents).unwrap();
+    let mut second = pathbox.reopen(&args[0]).unwrap();
+    contents.clear();
+    second.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "some data\n");
+
+    // Only whole grants can be reopened.
+    for token in [format!("{}/input.txt", args[1]), "guest-path.x".to_owned()] {
+        assert_eq!(
+            pathbox.reopen(&token).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+}
+
+#[test]
+fn colon_paths_exist() {
+    let dir = tempfile::tempdir().unwrap();
+    let colon_name = dir.path().join("a.txt:b.txt");
+    let a_name = dir.path().join("a.txt");
+    fs::write(&colon_name, b"").unwrap();
+    let existing = colon_name.to_str().unwrap().to_owned();
+    let missing = format!("{}:c.txt", a_name.display());
+
+    // By default, colons always separate lists.
+    let mut pathbox = Pathbox::new(MagicLevel::Auto);
+    pathbox.process_arg(existing.clone()).unwrap();
+    assert_eq!(pathbox.num_grants(), 2);
+
+    let mut pathbox = Pathbox::new(MagicLevel::Auto);
+    pathbox.set_check_colon_paths_exist(true);
+    let arg = pathbox.process_arg(existing.clone()).unwrap();
+    assert!(!arg.contains(':'));
+    assert_eq!(pathbox.num_grants(), 1);
+    assert_eq!(
+        pathbox.grants().next().unwrap().original,
+        OsString::from(existing)
+    );
+
+    // Paths that don't exist are still lists.
+    let arg = pathbox.process_arg(missing).unwrap();
+    assert!(arg.contains(':'));
+    assert_eq!(pathbox.num_grants(), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn create_mode() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let real_output_name = dir.path().join("secret.txt");
+
+    let args = [
+        format!("%write:{}", real_output_name.display()),
+        format!("%mutable-dir:{}", dir.path().display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    pathbox.set_create_mode(0o600);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    pathbox.create(&args[0]).unwrap();
+    let mode = fs::metadata(&real_output_name)
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // Appending only creates files when asked to.
+    let real_log_name = dir.path().join("log.txt");
+    let log = pathbox
+        .process_arg(format!("%append:{}", real_log_name.display()))
+        .unwrap();
+    assert_eq!(
+        pathbox.append(&log).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    pathbox.append_create(&log).unwrap();
+    let mode = fs::metadata(&real_log_name).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let real_index_name = dir.path().join("index.txt");
+    let index = pathbox
+        .process_arg(format!("%append:{}", real_index_name.display()))
+        .unwrap();
+    pathbox.open_append_read(&index).unwrap();
+    let mode = fs::metadata(&real_index_name).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let (token, _file) = pathbox.open_temp(&args[1]).unwrap();
+    let name = token.rsplit('/').next().unwrap();
+    let mode = fs::metadata(dir.path().join(name))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn open_read_write_create() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_db_name = dir.path().join("db.sqlite");
+    let real_input_name = dir.path().join("input.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+
+    let args = [
+        format!("%rwc:{}", real_db_name.display()),
+        format!("%read:{}", real_input_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // The file is created if absent.
+    let mut db = pathbox.open_read_write_create(&args[0]).unwrap();
+    db.write_all(b"header").unwrap();
+    drop(db);
+
+    // And preserved if present.
+    let mut db = pathbox.open_read_write_create(&args[0]).unwrap();
+    let mut contents = String::new();
+    db.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "header");
+    db.write_all(b" body").unwrap();
+    assert_eq!(fs::read(&real_db_name).unwrap(), b"header body");
+
+    assert_eq!(
+        pathbox.open_read_write_create(&args[1]).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn fs_facade() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    let real_output_name = dir.path().join("output.txt");
+    let real_moved_name = dir.path().join("moved.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_input_name.display()),
+        format!("%write:{}", real_output_name.display()),
+        format!("%write:{}", real_moved_name.display()),
+        format!("%dir:{}", dir.path().display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    assert_eq!(
+        pathbox::fs::read_to_string(&pathbox, &args[0]).unwrap(),
+        "some data\n"
+    );
+    assert_eq!(
+        pathbox::fs::read(&pathbox, &args[0]).unwrap(),
+        b"some data\n"
+    );
+    assert_eq!(pathbox::fs::metadata(&pathbox, &args[0]).unwrap().len(), 10);
+    assert!(pathbox::fs::metadata(&pathbox, &args[3]).unwrap().is_dir());
+
+    assert_eq!(pathbox::fs::copy(&pathbox, &args[0], &args[1]).unwrap(), 10);
+    assert_eq!(fs::read(&real_output_name).unwrap(), b"some data\n");
+    pathbox::fs::write(&pathbox, &args[1], "other data\n").unwrap();
+    assert_eq!(fs::read(&real_output_name).unwrap(), b"other data\n");
+
+    pathbox::fs::rename(&pathbox, &args[1], &args[2]).unwrap();
+    assert!(!real_output_name.exists());
+    assert_eq!(fs::read(&real_moved_name).unwrap(), b"other data\n");
+
+    let mut names = pathbox::fs::read_dir(&pathbox, &args[3])
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["input.txt", "moved.txt"]);
+
+    pathbox::fs::remove_file(&pathbox, &args[2]).unwrap();
+    assert!(!real_moved_name.exists());
+
+    // The usual access checks apply.
+    assert_eq!(
+        pathbox::fs::write(&pathbox, &args[0], "nope")
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        pathbox::fs::remove_file(&pathbox, &args[0])
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(fs::read(&real_input_name).unwrap(), b"some data\n");
+}
+
+#[test]
+fn access_none() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+
+    let args = [format!("%none:{}", real_input_name.display())];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+    assert_eq!(pathbox.num_grants(), 1);
+
+    // The path is translated, but can't be opened in any way.
+    assert!(!pathbox.exists(&args[0]));
+    for err in [
+        pathbox.open(&args[0]).unwrap_err(),
+        pathbox.create(&args[0]).unwrap_err(),
+        pathbox.append(&args[0]).unwrap_err(),
+        pathbox.open_read_write_create(&args[0]).unwrap_err(),
+    ] {
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+    assert_eq!(
+        pathbox.open_dir(&args[0]).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(fs::read(&real_input_name).unwrap(), b"some data\n");
+}
+
+#[test]
+fn open_resolved() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+
+    let args = [format!("%read:{}", real_input_name.display())];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    let (mut file, path) = pathbox.open_resolved(&args[0]).unwrap();
+    assert_eq!(path, real_input_name);
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "some data\n");
+
+    assert_eq!(
+        pathbox.open_resolved("guest-path.x").unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn open_executable() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_script_name = dir.path().join("script.sh");
+    fs::write(&real_script_name, b"echo hello\n").unwrap();
+
+    let args = [
+        format!("%read-exec:{}", real_script_name.display()),
+        format!("%read:{}", real_script_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+    assert_eq!(args[0], args[1]);
+
+    // Merging `Read` into `ReadExec` leaves `ReadExec`.
+    assert_eq!(pathbox.grants().next().unwrap().access, Access::ReadExec);
+    let mut contents = String::new();
+    pathbox
+        .open_executable(&args[0])
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "echo hello\n");
+    pathbox.open(&args[0]).unwrap();
+
+    // `Read` access alone doesn't permit executing.
+    let real_input_name = dir.path().join("input.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+    let input = pathbox
+        .process_arg(format!("%read:{}", real_input_name.display()))
+        .unwrap();
+    assert_eq!(
+        pathbox.open_executable(&input).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn open_dir_hides_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_dir_name = dir.path().join("secret-name");
+    fs::create_dir(&real_dir_name).unwrap();
+    let real = real_dir_name.to_str().unwrap().to_owned();
+
+    let args = [format!("%dir:{}", real)];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    let dir_view = pathbox.open_dir(&args[0]).unwrap();
+    assert!(!format!("{:?}", dir_view).contains("secret-name"));
+
+    let err = dir_view.open("missing.txt").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert!(!format!("{:?} {}", err, err).contains("secret-name"));
+
+    let err = dir_view.create("new.txt").unwrap_err();
+    assert!(!format!("{:?} {}", err, err).contains("secret-name"));
+}
+
+#[test]
+fn dir_traversal() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_dir_name = dir.path().join("granted");
+    fs::create_dir(&real_dir_name).unwrap();
+    fs::create_dir(real_dir_name.join("sub")).unwrap();
+    fs::write(real_dir_name.join("inner.txt"), b"inner\n").unwrap();
+    fs::write(dir.path().join("secret.txt"), b"secret\n").unwrap();
+
+    let args = [
+        format!("{}/", real_dir_name.display()),
+        format!("%dir:{}", real_dir_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Auto);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // Paths within the directory can be opened.
+    let mut contents = String::new();
+    pathbox
+        .open(&format!("{}/inner.txt", args[0]))
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "inner\n");
+    pathbox.open_dir(&format!("{}/sub", args[1])).unwrap();
+
+    // But `..` can't be used to escape it.
+    for path in [
+        format!("{}/../secret.txt", args[0]),
+        format!("{}/sub/../../secret.txt", args[0]),
+    ] {
+        assert_eq!(
+            pathbox.open(&path).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            pathbox.create(&path).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+    assert_eq!(
+        pathbox
+            .open_dir(&format!("{}/sub/../..", args[1]))
+            .unwrap_err()
+            .kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        fs::read(dir.path().join("secret.txt")).unwrap(),
+        b"secret\n"
+    );
+}
+
+#[test]
+fn file_traversal() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_dir_name = dir.path().join("sub");
+    fs::create_dir(&real_dir_name).unwrap();
+    let real_input_name = real_dir_name.join("input.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+    fs::write(dir.path().join("secret.txt"), b"secret\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_input_name.display()),
+        format!("%write:{}", real_input_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // Appending `..` to a file's internal filename doesn't escape it.
+    let path = format!("{}/../../secret.txt", args[0]);
+    for err in [
+        pathbox.open(&path).unwrap_err(),
+        pathbox.create(&path).unwrap_err(),
+        pathbox.open_range(&path).err().unwrap(),
+        pathbox.host_path(&path, Access::Read).unwrap_err(),
+    ] {
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+    assert_eq!(
+        fs::read(dir.path().join("secret.txt")).unwrap(),
+        b"secret\n"
+    );
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn open_mmap() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    let real_output_name = dir.path().join("output.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_input_name.display()),
+        format!("%write:{}", real_output_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // SAFETY: Nothing modifies the files while they're mapped.
+    let mmap = unsafe { pathbox.open_mmap(&args[0]) }.unwrap();
+    assert_eq!(&mmap[..], b"some data\n");
+
+    assert_eq!(
+        unsafe { pathbox.open_mmap(&args[1]) }.unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+}
+
+#[test]
+fn grant_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("file.txt"), b"contents\n").unwrap();
+
+    let mut pathbox = Pathbox::new(MagicLevel::Auto);
+    let (token, dir_view) = pathbox.grant_dir(dir.path(), false).unwrap();
+    assert_eq!(dir_view.read("file.txt").unwrap(), b"contents\n");
+    assert_eq!(
+        pathbox.translate_in(dir.path()).as_deref(),
+        Some(token.as_str())
+    );
+    let grant = pathbox.grants().next().unwrap();
+    assert_eq!(grant.access, Access::ReadonlyDir);
+    pathbox.open_dir(&token).unwrap();
+    pathbox.open_mutable_dir(&token).unwrap_err();
+
+    // Granting it again as mutable widens the existing grant.
+    let (again, dir_view) = pathbox.grant_dir(dir.path(), true).unwrap();
+    assert_eq!(again, token);
+    dir_view.write("new.txt", b"new\n").unwrap();
+    pathbox.open_mutable_dir(&token).unwrap();
+
+    // If the directory can't be opened, nothing is granted.
+    let missing = dir.path().join("missing");
+    assert_eq!(
+        pathbox.grant_dir(&missing, false).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+    assert_eq!(pathbox.num_grants(), 1);
+}
+
+#[test]
+fn read_write() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("input.txt");
+    let real_output_name = dir.path().join("output.txt");
+    fs::write(&real_input_name, b"some data\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_input_name.display()),
+        format!("%write:{}", real_output_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    let data = pathbox.read(&args[0]).unwrap();
+    assert_eq!(data, b"some data\n");
+    pathbox.write(&args[1], &data).unwrap();
+    assert_eq!(fs::read(&real_output_name).unwrap(), b"some data\n");
+
+    assert_eq!(
+        pathbox.read(&args[1]).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        pathbox.write(&args[0], b"").unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(fs::read(&real_input_name).unwrap(), b"some data\n");
+}
+
+#[test]
+fn cache_dirs() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_dir_name = dir.path().join("dir");
+    let moved_dir_name = dir.path().join("moved");
+    fs::create_dir(&real_dir_name).unwrap();
+    fs::write(real_dir_name.join("file.txt"), b"contents\n").unwrap();
+
+    let args = [format!("%dir:{}", real_dir_name.display())];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    pathbox.set_cache_dirs(true);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    let first = pathbox.open_dir(&args[0]).unwrap();
+    assert_eq!(first.read("file.txt").unwrap(), b"contents\n");
+
+    // Once the directory has been opened, the cached handle is reused, so
+    // moving the directory on the host isn't observed.
+    fs::rename(&real_dir_name, &moved_dir_name).unwrap();
+    let second = pathbox.open_dir(&args[0]).unwrap();
+    assert_eq!(second.read("file.txt").unwrap(), b"contents\n");
+    drop(first);
+    assert_eq!(second.read("file.txt").unwrap(), b"contents\n");
+
+    // Without the cache, the directory is opened by its path each time.
+    pathbox.set_cache_dirs(false);
+    assert_eq!(
+        pathbox.open_dir(&args[0]).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn regular_files_only() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_fifo_name = dir.path().join("fifo");
+    let real_file_name = dir.path().join("file.txt");
+    let status = std::process::Command::new("mkfifo")
+        .arg(&real_fifo_name)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    fs::write(&real_file_name, b"contents\n").unwrap();
+
+    let args = [
+        format!("%read:{}", real_fifo_name.display()),
+        format!("%read:{}", real_file_name.display()),
+        "%read:/dev/null".to_owned(),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // By default, special files may be opened.
+    pathbox.open(&args[2]).unwrap();
+
+    // Opening a FIFO without a writer would block, so this only checks that
+    // it's rejected when special files are disallowed.
+    pathbox.set_regular_files_only(true);
+    assert_eq!(
+        pathbox.open(&args[0]).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        pathbox.open(&args[2]).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        pathbox.open_range(&args[0]).err().unwrap().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(fs::read_to_string(&real_file_name).unwrap(), "contents\n");
+    pathbox.open(&args[1]).unwrap();
+}
+
+#[test]
+fn errors_hide_host_paths() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_missing_name = dir.path().join("missing.txt");
+    let real_missing_dir_name = dir.path().join("missing");
+    let real_dir_name = dir.path().join("dir");
+    fs::create_dir(&real_dir_name).unwrap();
+
+    let args = [
+        format!("%rwc:{}", real_missing_name.display()),
+        format!("%dir:{}", real_missing_dir_name.display()),
+        format!("%read:{}", real_dir_name.display()),
+        format!("%write:{}/..", real_dir_name.display()),
+    ];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    let host = dir.path().display().to_string();
+    let errors = [
+        pathbox.open(&args[0]).unwrap_err(),
+        pathbox.open_dir(&args[1]).unwrap_err(),
+        pathbox.read(&args[2]).unwrap_err(),
+        pathbox::fs::metadata(&pathbox, &args[0]).unwrap_err(),
+        pathbox::fs::remove_file(&pathbox, &args[0]).unwrap_err(),
+        pathbox::fs::rename(&pathbox, &args[0], &args[0]).unwrap_err(),
+    ];
+    for err in errors {
+        assert!(!err.to_string().contains(&host), "{}", err);
+    }
+
+    // Errors which pathbox builds itself name the file, so check that they
+    // name it by its internal filename.
+    pathbox.set_regular_files_only(true);
+    let errors = [
+        (pathbox.read(&args[2]).unwrap_err(), &args[2]),
+        (
+            pathbox::fs::remove_file(&pathbox, &args[3]).unwrap_err(),
+            &args[3],
+        ),
+    ];
+    for (err, arg) in errors {
+        let message = err.to_string();
+        assert!(message.contains(arg.as_str()), "{}", message);
+        assert!(!message.contains(&host), "{}", message);
+    }
+}
+
+#[test]
+fn open_append_read() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_log_name = dir.path().join("log.txt");
+    fs::write(&real_log_name, b"first\n").unwrap();
+
+    let args = [format!("%read:{}", real_log_name.display())];
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox.process_args(args.into_iter()).unwrap();
+
+    // Read access alone isn't enough.
+    assert_eq!(
+        pathbox.open_append_read(&args[0]).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let args = pathbox
+        .process_args([format!("%append:{}", real_log_name.display())].into_iter())
+        .unwrap();
+    let mut file = pathbox.open_append_read(&args[0]).unwrap();
+    file.write_all(b"second\n").unwrap();
+    file.seek(SeekFrom::Start(0)).unwrap();
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "first\nsecond\n");
+
+    // Appends still go to the end after seeking.
+    file.write_all(b"third\n").unwrap();
+    assert_eq!(
+        fs::read_to_string(&real_log_name).unwrap(),
+        "first\nsecond\nthird\n"
+    );
+}
+
+#[test]
+fn readable_tokens_with_extensions() {
+    let dir = tempfile::tempdir().unwrap();
+    let real_input_name = dir.path().join("data.v2.tar.gz");
+    fs::write(&real_input_name, b"compressed\n").unwrap();
+
+    let mut pathbox = Pathbox::new(MagicLevel::Auto);
+    pathbox.set_readable_tokens(true);
+    let args = pathbox
+        .process_args([real_input_name.display().to_string()].into_iter())
+        .unwrap();
+    assert_eq!(args, ["guest-path.1-data.v2.tar.gz"]);
+    assert_eq!(
+        Pathbox::split_guest_extension(&args[0]),
+        ("guest-path.1-data", ".v2.tar.gz")
+    );
+    assert_eq!(pathbox.read(&args[0]).unwrap(), b"compressed\n");
+
+    // The token with a different extension isn't the same file.
+    let (base, _ext) = Pathbox::split_guest_extension(&args[0]);
+    assert_eq!(
+        pathbox.read(&format!("{}.tar", base)).unwrap_err().kind(),
+        io::ErrorKind::NotFound
+    );
+}
+
+#[test]
+fn fs_mutable_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.txt"), b"data\n").unwrap();
+
+    // A read-only directory grant permits neither renaming nor removing.
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let token = pathbox
+        .process_arg(format!("%dir:{}", dir.path().display()))
+        .unwrap();
+    let a = format!("{}/a.txt", token);
+    let b = format!("{}/b.txt", token);
+    assert_eq!(
+        pathbox::fs::rename(&pathbox, &a, &b).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert_eq!(
+        pathbox::fs::remove_file(&pathbox, &a).unwrap_err().kind(),
+        io::ErrorKind::PermissionDenied
+    );
+    assert!(dir.path().join("a.txt").exists());
+
+    let mut pathbox = Pathbox::new(MagicLevel::Escapes);
+    let token = pathbox
+        .process_arg(format!("%mutable-dir:{}", dir.path().display()))
+        .unwrap();
+    let a = format!("{}/a.txt", token);
+    let b = format!("{}/b.txt", token);
+    pathbox::fs::rename(&pathbox, &a, &b).unwrap();
+    assert!(!dir.path().join("a.txt").exists());
+    assert_eq!(fs::read(dir.path().join("b.txt")).unwrap(), b"data\n");
+    pathbox::fs::remove_file(&pathbox, &b).unwrap();
+    assert!(!dir.path().join("b.txt").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn fs_symlink_escape() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let victim = outside.path().join("victim.txt");
+    fs::write(&victim, b"precious\n").unwrap();
+    fs::write(dir.path().join("a.txt"), b"inside\n").unwrap();
+    std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+
+    let mut pathbox = Pathbox::new(MagicLevel::Auto);
+    let token = pathbox
+        .process_arg(format!("{}/", dir.path().display()))
+        .unwrap();
+    let escape = format!("{}/link/victim.txt", token);
+    let inside = format!("{}/a.txt", token);
+
+    pathbox::fs::metadata(&pathbox, &escape).unwrap_err();
+    pathbox::fs::remove_file(&pathbox, &escape).unwrap_err();
+    pathbox::fs::rename(&pathbox, &escape, &format!("{}/moved.txt", token)).unwrap_err();
+    pathbox::fs::rename(&pathbox, &inside, &format!("{}/link/new.txt", token)).unwrap_err();
+    assert_eq!(fs::read(&victim).unwrap(), b"precious\n");
+    assert!(!outside.path().join("new.txt").exists());
+    assert!(!dir.path().join("moved.txt").exists());
+
+    // Paths which stay within the directory still work.
+    assert_eq!(pathbox::fs::metadata(&pathbox, &inside).unwrap().len(), 7);
+    pathbox::fs::rename(&pathbox, &inside, &format!("{}/b.txt", token)).unwrap();
+    pathbox::fs::remove_file(&pathbox, &format!("{}/b.txt", token)).unwrap();
+    assert!(fs::read_dir(dir.path()).unwrap().all(|entry| {
+        let name = entry.unwrap().file_name();
+        name == "link"
+    }));
+}
+
+#[cfg(unix)]
+#[test]
+fn open_range_symlink_escape() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    fs::write(outside.path().join("victim.txt"), b"precious\n").unwrap();
+    fs::write(dir.path().join("a.txt"), b"inside\n").unwrap();
+    std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+
+    let mut pathbox = Pathbox::new(MagicLevel::Auto);
+    let token = pathbox
+        .process_arg(format!("{}/", dir.path().display()))
+        .unwrap();
+    let outside_name = outside.path().file_name().unwrap().to_str().unwrap();
+
+    pathbox
+        .open_range(&format!("{}/link/victim.txt", token))
+        .unwrap_err();
+    pathbox
+        .open_range(&format!("{}/../{}/victim.txt", token, outside_name))
+        .unwrap_err();
+
+    let mut file = pathbox.open_range(&format!("{}/a.txt", token)).unwrap();
+    assert_eq!(file.range(), 0..7);
+    let mut contents = String::new();
+    file.read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "inside\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn open_resolved_symlink_escape() {
+    let dir = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    fs::write(outside.path().join("victim.txt"), b"precious\n").unwrap();
+    fs::write(dir.path().join("a.txt"), b"inside\n").unwrap();
+    std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+
+    let mut pathbox = Pathbox::new(MagicLevel::Auto);
+    let token = pathbox
+        .process_arg(format!("{}/", dir.path().display()))
+        .unwrap();
+    let escape = format!("{}/link/victim.txt", token);
+    let inside = format!("{}/a.txt", token);
+
+    pathbox.open_resolved(&escape).unwrap_err();
+    assert!(!pathbox.exists(&escape));
+
+    let (_file, host) = pathbox.open_resolved(&inside).unwrap();
+    assert_eq!(host, dir.path().join("a.txt"));
+
+    assert!(pathbox.exists(&inside));
+    assert!(pathbox.exists(&token));
+    assert!(!pathbox.exists(&format!("{}/missing.txt", token)));
+}